@@ -1,52 +1,30 @@
 //! 这个实例进行登录并查询一个需要登录的接口
-use std::time::Duration;
-
 use anyhow::*;
+use biliapi::login::{QrLogin, QrLoginState};
 use biliapi::Request;
+use futures::StreamExt;
 use log::*;
-use qrcode::{render::unicode, QrCode};
-use tokio::time::sleep;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv()?;
     pretty_env_logger::init();
 
-    let client = biliapi::connection::new_client()?;
-    let request = biliapi::requests::QrLoginRequest::request(&client, ()).await?;
-
-    let code = QrCode::new(request.url.as_bytes())?;
-    let string = code
-        .render::<unicode::Dense1x2>()
-        .dark_color(unicode::Dense1x2::Light)
-        .light_color(unicode::Dense1x2::Dark)
-        .build();
+    let client = biliapi::connection::Client::new()?;
+    let login = QrLogin::start(&client).await?;
     info!("请使用哔哩哔哩手机客户端扫描下面的二维码以登录");
-    println!("{}", string);
+    println!("{}", login.render_terminal()?);
 
-    // 先等5秒
-    sleep(Duration::from_secs(5)).await;
-    // 每隔3秒轮询一次，最多轮询10次
-    let mut retry = 0;
-    loop {
-        sleep(Duration::from_secs(3)).await;
-        let resp =
-            biliapi::requests::CheckQrLogin::request(&client, request.oauth_key.clone()).await?;
-        match resp.is_success() {
-            Some(true) => {
-                info!("登录成功！");
-                break;
-            }
-            Some(false) => {
-                bail!("登录失败");
-            }
-            None => {
-                if retry > 10 {
-                    bail!("登录超时了");
-                }
+    let mut states = login.states();
+    while let Some(state) = states.next().await {
+        match state? {
+            QrLoginState::WaitingForScan => info!("等待扫码"),
+            QrLoginState::ScannedWaitingConfirm => info!("已扫码，请在手机上确认"),
+            QrLoginState::Expired => bail!("二维码已失效"),
+            QrLoginState::Success { cookies, .. } => {
+                info!("登录成功！uid = {}", cookies.dede_user_id);
             }
         }
-        retry += 1;
     }
 
     // 查询一个只有登录状态能查询的 API
@@ -1,38 +1,64 @@
 //! 连接模块，包括 http client 和 websocket （直播间）连接
 //!
 #[cfg(feature = "live")]
-use futures::{stream::SplitStream, FutureExt, Stream, StreamExt};
-use reqwest::Client;
+use chrono::{DateTime, Local};
+#[cfg(feature = "live")]
+use futures::{FutureExt, Stream, StreamExt};
 #[cfg(feature = "live")]
 use std::{
     collections::VecDeque,
     future::Future,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 #[cfg(feature = "live")]
-type WebSocketStream = async_tungstenite::WebSocketStream<async_tungstenite::tokio::ConnectStream>;
-#[cfg(feature = "live")]
-use async_tungstenite::tungstenite::Error as WsError;
+use crate::ws_protocol;
 
+mod client;
+pub use client::{Client, ClientBuilder, Host, UserAgent};
+mod retry;
+pub(crate) use retry::with_retry;
+pub use retry::{RateLimit, RetryPolicy};
 #[cfg(feature = "live")]
-use crate::ws_protocol;
+mod builder;
+#[cfg(feature = "live")]
+pub(crate) use builder::resolve_room_id;
+#[cfg(feature = "live")]
+pub use builder::LiveConnectionBuilder;
+#[cfg(feature = "live")]
+mod hub;
+#[cfg(feature = "live")]
+pub use hub::{ConnectionState, LiveHub, RoomStatus};
+#[cfg(feature = "live")]
+mod reconnect;
+#[cfg(feature = "live")]
+pub use reconnect::{LiveMessage, ReconnectPolicy, ReconnectingConnection};
+#[cfg(feature = "live")]
+mod sender;
 #[cfg(feature = "live")]
-type WsResult<T> = Result<T, WsError>;
+pub use sender::LiveSender;
+#[cfg(feature = "live")]
+mod transport;
+#[cfg(feature = "live")]
+pub use transport::Transport;
 
 /// 创建一个新的 http 连接
-pub fn new_client() -> reqwest::Result<Client> {
+///
+/// [`Request`][`crate::Request`] 现在需要 [`Client`]，可以用 [`Client::from_reqwest`] 转换
+#[deprecated(note = "使用 `Client::new` 或者 `ClientBuilder`")]
+pub fn new_client() -> reqwest::Result<reqwest::Client> {
     const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
     trace!("user agent name: {}", USER_AGENT);
-    Client::builder()
+    reqwest::Client::builder()
         .user_agent(USER_AGENT)
         .cookie_store(true)
         .build()
 }
 
 #[cfg(feature = "live")]
-/// 直播间 websocket 连接，实现了 [`Stream`][`futures::Stream`]
+/// 直播间连接（websocket 或 TCP），实现了 [`Stream`][`futures::Stream`]
 ///
 /// # Example
 /// ```no_run
@@ -49,75 +75,409 @@ pub fn new_client() -> reqwest::Result<Client> {
 /// ```
 pub struct LiveConnection {
     room_id: u64,
-    heartbeat_future: Pin<Box<dyn Future<Output = WsResult<()>> + Send>>,
-    read: SplitStream<WebSocketStream>,
+    heartbeat_future: Pin<Box<dyn Future<Output = crate::Result<()>> + Send>>,
+    read: transport::PacketStream,
+    sender: LiveSender,
+    /// 连接时使用的 auth，切换房间时复用其中的身份信息
+    auth: ws_protocol::AuthBody,
+    /// 等待 AuthReply 和 ChangeRoomReply 的超时时间
+    change_room_timeout: Duration,
     buffered_msg: VecDeque<ws_protocol::Packet>,
+    /// 超过这个时间没有收到任何包就认为连接已经断开
+    watchdog: Option<(Duration, Pin<Box<tokio::time::Sleep>>)>,
+    last_packet_at: DateTime<Local>,
+    last_heartbeat_reply_at: Option<DateTime<Local>>,
+    /// 出错之后不再继续 poll
+    closed: bool,
 }
 #[cfg(feature = "live")]
 impl LiveConnection {
     /// 从 url 建立一个新连接，需要 room_id 和 token，这些数据可以从
-    /// [`InfoByRoom`][`crate::requests::InfoByRoom`] 拿到
-    pub async fn new(url: &str, room_id: u64, token: String) -> WsResult<Self> {
-        let (websocket, _http) = async_tungstenite::tokio::connect_async(url).await?;
-        let (write, read) = websocket.split();
-        // start sending
-        let heartbeat_future = Box::pin(async move {
-            use futures::prelude::*;
-            let mut write = write;
-            write
-                .send(ws_protocol::Packet::auth(room_id, &token).into())
-                .await?;
-            loop {
-                tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-                debug!("sending heartbeat...");
-                write
-                    .send(ws_protocol::Packet::heartbeat().into())
-                    .await
-                    .map_err(|e| {
-                        debug!("failed to send heartbeat: {:?}", e);
-                        e
-                    })?;
+    /// [`DanmuInfo`][`crate::requests::DanmuInfo`] 拿到
+    ///
+    /// token 错误时返回 [`LiveAuthFailed`][`crate::Error::LiveAuthFailed`]
+    pub async fn new(url: &str, room_id: u64, token: String) -> crate::Result<Self> {
+        Self::builder().connect(url, room_id, token).await
+    }
+
+    /// 只需要房间号（可以是短号）就可以连接，见 [`LiveConnectionBuilder::connect_room`]
+    ///
+    /// ```no_run
+    /// # use biliapi::connection::LiveConnection;
+    /// # tokio_test::block_on(async {
+    /// let client = biliapi::connection::Client::new().unwrap();
+    /// let con = LiveConnection::connect(&client, 1).await.unwrap();
+    /// assert_eq!(con.room_id(), 5440);
+    /// # });
+    /// ```
+    pub async fn connect(client: &Client, room_id: u64) -> crate::Result<Self> {
+        Self::builder().connect_room(client, room_id).await
+    }
+
+    /// 使用 [`LiveConnectionBuilder`] 配置 auth 身份、心跳间隔等
+    pub fn builder() -> LiveConnectionBuilder {
+        LiveConnectionBuilder::new()
+    }
+
+    /// 当前的直播间号，[`change_room`][`LiveConnection::change_room`] 成功后会改变
+    pub fn room_id(&self) -> u64 {
+        self.room_id
+    }
+
+    /// 在同一个连接上切换到另一个直播间，token 需要是新直播间的，可以从
+    /// [`DanmuInfo`][`crate::requests::DanmuInfo`] 拿到
+    ///
+    /// 收到 ChangeRoomReply 之前的 packet 仍然属于原来的直播间，之后的 packet 都标记为新的房间号。
+    /// 服务器拒绝时返回 [`LiveChangeRoomFailed`][`crate::Error::LiveChangeRoomFailed`]，
+    /// 连接保持在原来的直播间。
+    ///
+    /// 超时（见 [`LiveConnectionBuilder::change_room_timeout`]）没有收到回复或者回复无法解析时
+    /// 不知道服务器是否已经切换，连接会被关闭，已经收到的 packet 仍然可以读出。
+    /// 等待回复时心跳和看门狗照常工作
+    pub async fn change_room(&mut self, room_id: u64, token: String) -> crate::Result<()> {
+        use ws_protocol::{KnownOperation, Operation, Packet};
+
+        let auth = ws_protocol::AuthBody {
+            room_id,
+            key: token,
+            ..self.auth.clone()
+        };
+        let body = serde_json::to_string(&auth).unwrap();
+        let packet = Packet::new(
+            Operation::Known(KnownOperation::ChangeRoom),
+            body,
+            self.room_id,
+        );
+        self.sender.send(packet).await?;
+
+        let timeout = self.change_room_timeout;
+        let wait_reply = async {
+            while let Some(packets) = futures::future::poll_fn(|cx| self.poll_packets(cx)).await {
+                let mut packets = packets?.into_iter();
+                for packet in packets.by_ref() {
+                    if packet.operation != Operation::Known(KnownOperation::ChangeRoomReply) {
+                        let room_id = self.room_id;
+                        self.buffered_msg.push_back(Packet { room_id, ..packet });
+                        continue;
+                    }
+                    let code = builder::reply_code(&packet.body);
+                    // 同一批中剩下的 packet 属于切换后的直播间
+                    let current = match code {
+                        Ok(0) => room_id,
+                        _ => self.room_id,
+                    };
+                    self.buffered_msg.extend(packets.map(|packet| Packet {
+                        room_id: current,
+                        ..packet
+                    }));
+                    match code {
+                        Ok(0) => return Ok(()),
+                        Ok(code) => {
+                            warn!("change room rejected, body = {}", packet.body);
+                            return Err(crate::Error::LiveChangeRoomFailed {
+                                room_id,
+                                code: Some(code),
+                            });
+                        }
+                        Err(e) => {
+                            warn!("invalid change room reply: {}", packet.body);
+                            self.closed = true;
+                            return Err(e);
+                        }
+                    }
+                }
             }
-        });
-        Ok(Self {
+            self.closed = true;
+            Err(crate::Error::LiveChangeRoomFailed {
+                room_id,
+                code: None,
+            })
+        };
+        let result = tokio::time::timeout(timeout, wait_reply).await;
+        if result.is_err() {
+            // 之后到达的回复仍然可能让服务器切换房间，不能继续使用这个连接
+            warn!("no change room reply in {:?}, closing connection", timeout);
+            self.closed = true;
+            if let Err(e) = self.sender.close().await {
+                debug!("failed to close connection: {}", e);
+            }
+        }
+        result.unwrap_or(Err(crate::Error::LiveChangeRoomFailed {
             room_id,
-            heartbeat_future,
-            read,
-            buffered_msg: VecDeque::new(),
-        })
+            code: None,
+        }))?;
+        debug!("changed from room {} to room {}", self.room_id, room_id);
+        self.room_id = room_id;
+        self.auth = auth;
+        Ok(())
+    }
+
+    /// 发送端，可以用来发送任意的 packet 或者主动关闭连接
+    pub fn sender(&self) -> LiveSender {
+        self.sender.clone()
+    }
+
+    /// 最后一次收到任何包的时间，可以用来监控连接状态
+    pub fn last_packet_at(&self) -> DateTime<Local> {
+        self.last_packet_at
+    }
+
+    /// 最后一次收到心跳回复的时间
+    pub fn last_heartbeat_reply_at(&self) -> Option<DateTime<Local>> {
+        self.last_heartbeat_reply_at
+    }
+
+    /// 收到了新的包，更新时间戳并重置看门狗
+    fn touch(&mut self, packets: &[ws_protocol::Packet]) {
+        self.last_packet_at = Local::now();
+        if packets.iter().any(|p| {
+            p.operation
+                == ws_protocol::Operation::Known(ws_protocol::KnownOperation::HeartbeatReply)
+        }) {
+            self.last_heartbeat_reply_at = Some(self.last_packet_at);
+        }
+        if let Some((timeout, sleep)) = self.watchdog.as_mut() {
+            sleep.as_mut().reset(tokio::time::Instant::now() + *timeout);
+        }
     }
 }
 #[cfg(feature = "live")]
-impl Stream for LiveConnection {
-    type Item = crate::Result<ws_protocol::Packet>;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // try poll heartbeat first
+impl LiveConnection {
+    /// 读取下一批 packet，同时驱动心跳和看门狗
+    fn poll_packets(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<crate::Result<Vec<ws_protocol::Packet>>>> {
         match self.heartbeat_future.poll_unpin(cx) {
             Poll::Ready(Err(e)) => {
                 warn!("The heartbeat future exited unexpectedly: {:?}", e);
-                return Poll::Ready(Some(Err(e.into())));
+                self.closed = true;
+                return Poll::Ready(Some(Err(e)));
             }
-            Poll::Ready(Ok(_)) => unreachable!(),
+            // 连接被主动关闭，心跳停止
+            Poll::Ready(Ok(_)) => self.heartbeat_future = Box::pin(futures::future::pending()),
             Poll::Pending => {}
         }
-        // try buffered messages
-        if let Some(msg) = self.buffered_msg.pop_front() {
+
+        match self.read.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(packets))) => {
+                self.touch(&packets);
+                return Poll::Ready(Some(Ok(packets)));
+            }
+            Poll::Ready(Some(Err(e))) => {
+                // 传输层的错误之后连接不能再使用，单个包的解析错误不影响后面的包
+                if matches!(
+                    e,
+                    crate::Error::WebSocket(_)
+                        | crate::Error::Io(_)
+                        | crate::Error::Protocol(ws_protocol::ParseError::IO(_))
+                ) {
+                    self.closed = true;
+                }
+                return Poll::Ready(Some(Err(e)));
+            }
+            Poll::Ready(None) => {
+                self.closed = true;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => {}
+        }
+
+        // 没有新的数据，检查看门狗
+        if let Some((timeout, sleep)) = self.watchdog.as_mut() {
+            if sleep.as_mut().poll(cx).is_ready() {
+                self.closed = true;
+                if self.sender.is_closed() {
+                    debug!("room {} closed without server response", self.room_id);
+                    return Poll::Ready(None);
+                }
+                warn!(
+                    "room {} received nothing in {:?}, connection seems dead",
+                    self.room_id, timeout
+                );
+                return Poll::Ready(Some(Err(crate::Error::LiveTimeout(*timeout))));
+            }
+        }
+        Poll::Pending
+    }
+}
+#[cfg(feature = "live")]
+impl Stream for LiveConnection {
+    type Item = crate::Result<ws_protocol::Packet>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        // 关闭之前已经收到的 packet 仍然读出
+        if let Some(msg) = this.buffered_msg.pop_front() {
             return Poll::Ready(Some(Ok(msg)));
         }
+        if this.closed {
+            return Poll::Ready(None);
+        }
 
-        // now get a message
-        match self.read.poll_next_unpin(cx) {
-            Poll::Ready(Some(Ok(ws_message))) => {
-                let msgs = ws_protocol::Packet::from_ws_message(ws_message, self.room_id)?;
-                self.buffered_msg.extend(msgs);
-                match self.buffered_msg.pop_front() {
-                    Some(msg) => Poll::Ready(Some(Ok(msg))),
-                    None => Poll::Pending,
+        loop {
+            match futures::ready!(this.poll_packets(cx)) {
+                Some(Ok(mut msgs)) => {
+                    // 切换房间之后，解码器中的房间号已经过时了
+                    for msg in &mut msgs {
+                        msg.room_id = this.room_id;
+                    }
+                    this.buffered_msg.extend(msgs);
+                    if let Some(msg) = this.buffered_msg.pop_front() {
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                    // 如 ping 这样不产生 packet 的消息，继续读
                 }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "live"))]
+mod tests {
+    use super::*;
+    use crate::ws_protocol::{KnownOperation, Operation, Packet, PacketDecoder, ParseError};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use tokio_util::codec::FramedRead;
+
+    fn packet(op: KnownOperation, body: &str) -> Packet {
+        Packet::new(Operation::Known(op), body, 0)
+    }
+
+    #[tokio::test]
+    async fn test_change_room() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut read = FramedRead::new(read, PacketDecoder::new(0)).map(|p| p.unwrap());
+            drop(read.next().await);
+            write
+                .write_all(&packet(KnownOperation::AuthReply, r#"{"code":0}"#).to_bytes())
+                .await
+                .unwrap();
+            // 第一次切换成功，第二次失败
+            for code in [0, -1] {
+                let change = read.next().await.unwrap().remove(0);
+                assert_eq!(
+                    change.operation,
+                    Operation::Known(KnownOperation::ChangeRoom)
+                );
+                let body: serde_json::Value = serde_json::from_str(&change.body).unwrap();
+                let mut bytes = packet(KnownOperation::SendMsgReply, "before").to_bytes();
+                bytes.extend(
+                    packet(
+                        KnownOperation::ChangeRoomReply,
+                        &format!(r#"{{"code":{}}}"#, code),
+                    )
+                    .to_bytes(),
+                );
+                bytes.extend(
+                    packet(KnownOperation::SendMsgReply, &body["key"].to_string()).to_bytes(),
+                );
+                write.write_all(&bytes).await.unwrap();
             }
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
-            Poll::Ready(None) => Poll::Ready(None),
+            drop(read.next().await);
+        });
+
+        let mut con = LiveConnection::builder()
+            .connect(&url, 1, "t1".to_string())
+            .await
+            .unwrap();
+        con.change_room(2, "t2".to_string()).await.unwrap();
+        assert_eq!(con.room_id(), 2);
+        match con.change_room(3, "t3".to_string()).await {
+            Err(crate::Error::LiveChangeRoomFailed { room_id, code }) => {
+                assert_eq!((room_id, code), (3, Some(-1)))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(con.room_id(), 2);
+
+        let mut received = vec![];
+        for _ in 0..4 {
+            let p = con.next().await.unwrap().unwrap();
+            received.push((p.body, p.room_id));
         }
+        assert_eq!(
+            received,
+            vec![
+                ("before".to_string(), 1),
+                (r#""t2""#.to_string(), 2),
+                ("before".to_string(), 2),
+                (r#""t3""#.to_string(), 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_room_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut read = FramedRead::new(read, PacketDecoder::new(0)).map(|p| p.unwrap());
+            drop(read.next().await);
+            write
+                .write_all(&packet(KnownOperation::AuthReply, r#"{"code":0}"#).to_bytes())
+                .await
+                .unwrap();
+            drop(read.next().await);
+            // 不回复 ChangeRoomReply
+            write
+                .write_all(&packet(KnownOperation::SendMsgReply, "before").to_bytes())
+                .await
+                .unwrap();
+            while read.next().await.is_some() {}
+        });
+
+        let mut con = LiveConnection::builder()
+            .change_room_timeout(Duration::from_millis(100))
+            .connect(&url, 1, "t1".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            con.change_room(2, "t2".to_string()).await,
+            Err(crate::Error::LiveChangeRoomFailed {
+                room_id: 2,
+                code: None
+            })
+        ));
+        assert_eq!(con.room_id(), 1);
+        // 已经收到的 packet 仍然可以读出，之后连接关闭
+        let p = con.next().await.unwrap().unwrap();
+        assert_eq!((p.body.as_str(), p.room_id), ("before", 1));
+        assert!(con.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closed_after_read_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut buf).await;
+            socket
+                .write_all(&packet(KnownOperation::AuthReply, r#"{"code":0}"#).to_bytes())
+                .await
+                .unwrap();
+            // 发送 RST，客户端读取时出错
+            socket.set_zero_linger().unwrap();
+        });
+
+        let mut con = LiveConnection::builder()
+            .connect(&url, 1, "t1".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            con.next().await,
+            Some(Err(crate::Error::Protocol(ParseError::IO(_))))
+        ));
+        assert!(con.closed);
+        assert!(con.next().await.is_none());
     }
 }
@@ -0,0 +1,458 @@
+//! [`LiveConnection`] 的构造器
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use async_tungstenite::tungstenite::http::HeaderValue;
+use futures::StreamExt;
+use reqwest::{cookie::CookieStore, Url};
+
+use super::{transport, Client, LiveConnection, LiveSender, Transport};
+use crate::requests::{DanmuInfo, DanmuServer, InfoByRoom};
+use crate::ws_protocol::{self, AuthBody, KnownOperation, Operation, ParseError};
+use crate::Request;
+
+/// 心跳间隔的下限，避免间隔为 0 时不停地发送心跳
+const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 创建 [`LiveConnection`] 的构造器，可以配置 auth 时使用的身份、心跳间隔等
+///
+/// # Example
+/// ```no_run
+/// # use biliapi::connection::LiveConnection;
+/// # let (url, room_id, token) = ("", 1, "".to_string());
+/// # tokio_test::block_on(async {
+/// use std::time::Duration;
+/// let con = LiveConnection::builder()
+///     .uid(10086)
+///     .protover(biliapi::ws_protocol::magic::VER_BROTLI)
+///     .heartbeat_interval(Duration::from_secs(20))
+///     .connect(url, room_id, token)
+///     .await
+///     .unwrap();
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct LiveConnectionBuilder {
+    uid: Option<u64>,
+    buvid: Option<String>,
+    protover: u16,
+    platform: String,
+    clientver: String,
+    heartbeat_interval: Duration,
+    connect_timeout: Option<Duration>,
+    auth_timeout: Duration,
+    change_room_timeout: Duration,
+    watchdog: Option<u32>,
+    transport: Transport,
+    keep_raw: bool,
+    cookies: Option<Arc<dyn CookieStore>>,
+}
+
+impl Default for LiveConnectionBuilder {
+    fn default() -> Self {
+        let auth = AuthBody::new(0, "");
+        Self {
+            uid: None,
+            buvid: None,
+            protover: auth.protover,
+            platform: auth.platform,
+            clientver: auth.clientver,
+            heartbeat_interval: Duration::from_secs(30),
+            connect_timeout: None,
+            auth_timeout: Duration::from_secs(10),
+            change_room_timeout: Duration::from_secs(10),
+            watchdog: Some(3),
+            transport: Transport::default(),
+            keep_raw: false,
+            cookies: None,
+        }
+    }
+}
+
+impl LiveConnectionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// auth 时使用的 uid，不设置时会尝试从 cookie 的 DedeUserID 中读取，否则为 0（匿名）
+    pub fn uid(mut self, uid: u64) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// auth 时使用的 buvid，不设置时会尝试从 cookie 的 buvid3 中读取
+    pub fn buvid(mut self, buvid: impl Into<String>) -> Self {
+        self.buvid = Some(buvid.into());
+        self
+    }
+
+    /// 请求的协议版本，默认为 [`VER_ZLIB_COMPRESSED`][`ws_protocol::magic::VER_ZLIB_COMPRESSED`]，
+    /// 可以使用 [`VER_BROTLI`][`ws_protocol::magic::VER_BROTLI`] 来节省带宽，见 [`magic`][`ws_protocol::magic`]
+    pub fn protover(mut self, protover: u16) -> Self {
+        self.protover = protover;
+        self
+    }
+
+    /// auth 时上报的平台，默认为 web
+    pub fn platform(mut self, platform: impl Into<String>) -> Self {
+        self.platform = platform.into();
+        self
+    }
+
+    /// auth 时上报的客户端版本
+    pub fn clientver(mut self, clientver: impl Into<String>) -> Self {
+        self.clientver = clientver.into();
+        self
+    }
+
+    /// 发送心跳包的间隔，默认为 30 秒，最小为 10 毫秒
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval.max(MIN_HEARTBEAT_INTERVAL);
+        self
+    }
+
+    /// 建立连接的超时时间，websocket 和 `tcp://` 都适用，默认不超时
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// 发送 auth 包之后等待 AuthReply 的超时时间，默认为 10 秒
+    pub fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_timeout = timeout;
+        self
+    }
+
+    /// [`change_room`][`LiveConnection::change_room`] 等待 ChangeRoomReply 的超时时间，
+    /// 默认为 10 秒
+    pub fn change_room_timeout(mut self, timeout: Duration) -> Self {
+        self.change_room_timeout = timeout;
+        self
+    }
+
+    /// 连续多少个心跳间隔没有收到任何包（包括心跳回复）就认为连接已经断开，
+    /// 这时 stream 会返回 [`LiveTimeout`][`crate::Error::LiveTimeout`]。
+    ///
+    /// 默认为 3，None 表示关闭检测，Some(0) 视为 Some(1)
+    pub fn watchdog(mut self, missed_heartbeats: Option<u32>) -> Self {
+        self.watchdog = missed_heartbeats.map(|missed| missed.max(1));
+        self
+    }
+
+    /// 连接弹幕服务器使用的传输协议，只影响 [`server_url`][`LiveConnectionBuilder::server_url`]
+    /// 的结果；[`connect`][`LiveConnectionBuilder::connect`] 总是按照 url 的协议来连接
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// 在 [`Packet::raw`][`ws_protocol::Packet::raw`] 中保留原始的帧，用于协议调试和原样重放，默认关闭
+    pub fn keep_raw(mut self, keep_raw: bool) -> Self {
+        self.keep_raw = keep_raw;
+        self
+    }
+
+    /// 按照设置的传输协议选择服务器的 url
+    pub fn server_url(&self, server: &DanmuServer) -> String {
+        self.transport.url(server)
+    }
+
+    /// 使用和 http client 相同的 cookie，这样登录的账号可以拿到完整的数据
+    ///
+    /// 传入的就是 [`reqwest::ClientBuilder::cookie_provider`] 使用的 cookie store
+    pub fn cookies(mut self, cookies: Arc<dyn CookieStore>) -> Self {
+        self.cookies = Some(cookies);
+        self
+    }
+
+    /// 没有设置 cookie 时使用 client 的 cookie
+    pub(super) fn with_client_cookies(mut self, client: &Client) -> Self {
+        if self.cookies.is_none() {
+            self.cookies = client.cookies();
+        }
+        self
+    }
+
+    fn cookie_header(&self) -> Option<HeaderValue> {
+        let url = Url::parse("https://live.bilibili.com").unwrap();
+        let cookies = self.cookies.as_ref()?.cookies(&url)?;
+        HeaderValue::from_bytes(cookies.as_bytes()).ok()
+    }
+
+    /// 生成 auth 包的内容
+    pub fn auth_body(&self, room_id: u64, token: &str) -> AuthBody {
+        let cookie_header = self.cookie_header();
+        let cookie = |name: &str| -> Option<String> {
+            let header = cookie_header.as_ref()?.to_str().ok()?;
+            header.split(';').find_map(|pair| {
+                let (k, v) = pair.trim().split_once('=')?;
+                (k == name).then(|| v.to_string())
+            })
+        };
+        AuthBody {
+            uid: self
+                .uid
+                .or_else(|| cookie("DedeUserID")?.parse().ok())
+                .unwrap_or(0),
+            room_id,
+            protover: self.protover,
+            buvid: self.buvid.clone().or_else(|| cookie("buvid3")),
+            platform: self.platform.clone(),
+            clientver: self.clientver.clone(),
+            auth_type: 2,
+            key: token.to_string(),
+        }
+    }
+
+    /// 只需要房间号（可以是短号）就可以连接：先通过 [`InfoByRoom`] 拿到长房号，
+    /// 再通过 [`DanmuInfo`] 拿到 token 和服务器列表，依次尝试每个服务器直到连接成功
+    ///
+    /// 连接使用长房号，所以 [`Packet::room_id`][`ws_protocol::Packet::room_id`] 总是长房号。
+    /// 没有设置 [`cookies`][`LiveConnectionBuilder::cookies`] 时使用 client 的 cookie
+    pub async fn connect_room(
+        self,
+        client: &Client,
+        room_id: u64,
+    ) -> crate::Result<LiveConnection> {
+        let builder = self.with_client_cookies(client);
+        let room_id = resolve_room_id(client, room_id).await?;
+        let danmu_info = DanmuInfo::request(client, room_id).await?;
+        let mut last_error = crate::Error::DataNotFound;
+        for server in &danmu_info.servers {
+            let url = builder.server_url(server);
+            debug!("room {} connecting to {}", room_id, url);
+            match builder
+                .clone()
+                .connect(&url, room_id, danmu_info.token.clone())
+                .await
+            {
+                Ok(connection) => return Ok(connection),
+                Err(e) => {
+                    warn!("room {} failed to connect to {}: {}", room_id, url, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// 连接到 url，需要 room_id 和 token，这些数据可以从
+    /// [`DanmuInfo`][`crate::requests::DanmuInfo`] 拿到
+    ///
+    /// url 为 `tcp://host:port` 时使用原始 TCP 连接，否则使用 websocket
+    ///
+    /// 会等到收到服务器的 AuthReply 并且验证成功之后才返回
+    pub async fn connect(
+        self,
+        url: &str,
+        room_id: u64,
+        token: String,
+    ) -> crate::Result<LiveConnection> {
+        let connect = transport::connect(
+            url,
+            ws_protocol::PacketDecoder::new(room_id).keep_raw(self.keep_raw),
+            self.cookie_header(),
+        );
+        let (mut write, mut read) = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect).await.map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timeout")
+            })??,
+            None => connect.await?,
+        };
+
+        let auth = self.auth_body(room_id, &token);
+        write.send(ws_protocol::Packet::auth_with(&auth)).await?;
+
+        // 等待 AuthReply，在这之前收到的包先缓存起来
+        let mut buffered_msg = VecDeque::new();
+        let wait_auth_reply = async {
+            while let Some(packets) = read.next().await {
+                let mut packets = packets?.into_iter();
+                while let Some(packet) = packets.next() {
+                    if packet.operation == Operation::Known(KnownOperation::AuthReply) {
+                        // 同一批中 AuthReply 之后的包也要保留
+                        buffered_msg.extend(packets);
+                        return check_auth_reply(&packet.body);
+                    }
+                    buffered_msg.push_back(packet);
+                }
+            }
+            warn!("connection closed before auth reply, room_id = {}", room_id);
+            Err(crate::Error::LiveAuthFailed { code: None })
+        };
+        tokio::time::timeout(self.auth_timeout, wait_auth_reply)
+            .await
+            .map_err(|_| crate::Error::LiveAuthTimeout(self.auth_timeout))??;
+        debug!("room {} authenticated", room_id);
+
+        let sender = LiveSender::new(write);
+        let heartbeat_interval = self.heartbeat_interval;
+        let heartbeat_sender = sender.clone();
+        // start sending
+        let heartbeat_future = Box::pin(async move {
+            loop {
+                tokio::time::sleep(heartbeat_interval).await;
+                debug!("sending heartbeat...");
+                match heartbeat_sender
+                    .send(ws_protocol::Packet::heartbeat())
+                    .await
+                {
+                    Ok(()) => {}
+                    Err(crate::Error::LiveClosed) => return Ok(()),
+                    Err(e) => {
+                        debug!("failed to send heartbeat: {:?}", e);
+                        return Err(e);
+                    }
+                }
+            }
+        });
+        let watchdog = self.watchdog.map(|missed| {
+            let timeout = heartbeat_interval * missed;
+            let sleep = Box::pin(tokio::time::sleep(timeout));
+            (timeout, sleep)
+        });
+        Ok(LiveConnection {
+            room_id,
+            heartbeat_future,
+            read,
+            sender,
+            auth,
+            change_room_timeout: self.change_room_timeout,
+            buffered_msg,
+            watchdog,
+            last_packet_at: chrono::Local::now(),
+            last_heartbeat_reply_at: None,
+            closed: false,
+        })
+    }
+}
+
+/// 把短房号（如 1）转换为长房号（如 5440），长房号不变
+pub(crate) async fn resolve_room_id(client: &Client, room_id: u64) -> crate::Result<u64> {
+    let info = InfoByRoom::request(client, room_id).await?;
+    if info.room_info.room_id != room_id {
+        debug!("room {} resolved to {}", room_id, info.room_info.room_id);
+    }
+    Ok(info.room_info.room_id)
+}
+
+/// AuthReply 和 ChangeRoomReply 的 body 形如 `{"code":0}`
+pub(super) fn reply_code(body: &str) -> crate::Result<i64> {
+    #[derive(Deserialize)]
+    struct Reply {
+        code: i64,
+    }
+    let reply: Reply = serde_json::from_str(body).map_err(ParseError::from)?;
+    Ok(reply.code)
+}
+
+fn check_auth_reply(body: &str) -> crate::Result<()> {
+    match reply_code(body)? {
+        0 => Ok(()),
+        code => {
+            warn!("live auth rejected, body = {}", body);
+            Err(crate::Error::LiveAuthFailed { code: Some(code) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedCookies(&'static str);
+    impl CookieStore for FixedCookies {
+        fn set_cookies(&self, _: &mut dyn Iterator<Item = &reqwest::header::HeaderValue>, _: &Url) {
+        }
+        fn cookies(&self, _: &Url) -> Option<reqwest::header::HeaderValue> {
+            Some(reqwest::header::HeaderValue::from_static(self.0))
+        }
+    }
+
+    #[test]
+    fn test_auth_body_from_cookies() {
+        let builder = LiveConnectionBuilder::new().cookies(Arc::new(FixedCookies(
+            "SESSDATA=abc; DedeUserID=10086; buvid3=XYZ",
+        )));
+        let auth = builder.auth_body(5440, "token");
+        assert_eq!(auth.uid, 10086);
+        assert_eq!(auth.buvid.as_deref(), Some("XYZ"));
+        assert_eq!(auth.key, "token");
+
+        let auth = builder.uid(1).buvid("B").auth_body(5440, "token");
+        assert_eq!(auth.uid, 1);
+        assert_eq!(auth.buvid.as_deref(), Some("B"));
+    }
+
+    #[test]
+    fn test_check_auth_reply() {
+        assert!(check_auth_reply(r#"{"code":0}"#).is_ok());
+        match check_auth_reply(r#"{"code":-101}"#) {
+            Err(crate::Error::LiveAuthFailed { code }) => assert_eq!(code, Some(-101)),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(matches!(
+            check_auth_reply("not json"),
+            Err(crate::Error::Protocol(ParseError::Json(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_packets_after_auth_reply() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // AuthReply 和弹幕压缩在同一帧中
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let packets = [
+                ws_protocol::Packet::new(
+                    Operation::Known(KnownOperation::AuthReply),
+                    r#"{"code":0}"#,
+                    0,
+                ),
+                ws_protocol::Packet::new(
+                    Operation::Known(KnownOperation::SendMsgReply),
+                    r#"{"cmd":"LIVE","roomid":5440}"#,
+                    0,
+                ),
+            ];
+            stream
+                .write_all(&ws_protocol::Packet::compress(&packets, 2))
+                .await
+                .unwrap();
+            futures::future::pending::<()>().await;
+        });
+
+        let mut con = LiveConnectionBuilder::new()
+            .connect(&url, 5440, "token".to_string())
+            .await
+            .unwrap();
+        let packet = con.next().await.unwrap().unwrap();
+        assert_eq!(packet.body, r#"{"cmd":"LIVE","roomid":5440}"#);
+    }
+
+    #[test]
+    fn test_heartbeat_interval_clamped() {
+        let builder = LiveConnectionBuilder::new().heartbeat_interval(Duration::ZERO);
+        assert_eq!(builder.heartbeat_interval, MIN_HEARTBEAT_INTERVAL);
+    }
+
+    #[test]
+    fn test_watchdog_at_least_one() {
+        assert_eq!(
+            LiveConnectionBuilder::new().watchdog(Some(0)).watchdog,
+            Some(1)
+        );
+        assert_eq!(LiveConnectionBuilder::new().watchdog(None).watchdog, None);
+    }
+
+    #[test]
+    fn test_auth_body_anonymous() {
+        let auth = LiveConnectionBuilder::new().auth_body(5440, "token");
+        assert_eq!(auth, AuthBody::new(5440, "token"));
+    }
+}
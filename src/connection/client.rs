@@ -0,0 +1,565 @@
+//! http client 及其构造器
+
+use std::{collections::HashMap, fmt, future::Future, sync::Arc, time::Duration};
+
+use super::retry::{RateLimit, RetryPolicy, TokenBucket};
+use crate::requests::wbi::WbiCache;
+
+use reqwest::{
+    cookie::{CookieStore, Jar},
+    header::{self, HeaderMap, HeaderValue},
+    IntoUrl, Method, RequestBuilder, Response, Url,
+};
+
+/// b 站 API 使用的域名，可以通过 [`ClientBuilder::base_url`] 指向其他的服务器
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Host {
+    /// api.bilibili.com
+    Api,
+    /// api.live.bilibili.com
+    ApiLive,
+    /// passport.bilibili.com
+    Passport,
+    /// api.vc.bilibili.com
+    ApiVc,
+}
+
+impl Host {
+    pub const ALL: [Host; 4] = [Host::Api, Host::ApiLive, Host::Passport, Host::ApiVc];
+
+    pub fn domain(&self) -> &'static str {
+        match self {
+            Self::Api => "api.bilibili.com",
+            Self::ApiLive => "api.live.bilibili.com",
+            Self::Passport => "passport.bilibili.com",
+            Self::ApiVc => "api.vc.bilibili.com",
+        }
+    }
+
+    pub(crate) fn from_domain(domain: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|host| host.domain() == domain)
+    }
+}
+
+/// 常用的 User-Agent
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UserAgent {
+    /// `biliapi/版本号`，默认
+    #[default]
+    Library,
+    /// 桌面版 Chrome
+    Chrome,
+    /// 桌面版 Firefox
+    Firefox,
+    Custom(String),
+}
+
+impl UserAgent {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Library => concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+            Self::Chrome => "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+            Self::Firefox => "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0",
+            Self::Custom(ua) => ua,
+        }
+    }
+}
+
+impl From<&str> for UserAgent {
+    fn from(ua: &str) -> Self {
+        Self::Custom(ua.to_string())
+    }
+}
+
+impl From<String> for UserAgent {
+    fn from(ua: String) -> Self {
+        Self::Custom(ua)
+    }
+}
+
+/// [`ClientBuilder::cookie_provider`] 需要一个具体的类型
+struct SharedCookies(Arc<dyn CookieStore>);
+
+impl CookieStore for SharedCookies {
+    fn set_cookies(&self, headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        self.0.set_cookies(headers, url)
+    }
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        self.0.cookies(url)
+    }
+}
+
+/// 请求 b 站 API 使用的 http client，所有的 [`Request`][`crate::Request`] 都使用它
+///
+/// 可以像 [`reqwest::Client`] 一样使用，[`get`][`Client::get`]、[`post`][`Client::post`]、
+/// [`execute`][`Client::execute`] 等方法会按照 [`ClientBuilder::base_url`] 改写请求的地址。
+/// clone 的开销很小，会共享连接池、cookie 和频率限制。
+///
+/// 频率限制在 [`execute`][`Client::execute`] 和 [`send`][`Client::send`] 中等待，
+/// 所以构造好的请求应该交给它们发送，而不是直接调用 [`RequestBuilder::send`]
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    cookies: Option<Arc<dyn CookieStore>>,
+    base_urls: Arc<HashMap<Host, Url>>,
+    retry_policy: Option<RetryPolicy>,
+    rate_limits: Arc<HashMap<Host, TokenBucket>>,
+    wbi: Arc<WbiCache>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("inner", &self.inner)
+            .field("base_urls", &self.base_urls)
+            .field("retry_policy", &self.retry_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    /// 使用默认配置创建，同 `ClientBuilder::new().build()`
+    pub fn new() -> reqwest::Result<Self> {
+        ClientBuilder::new().build()
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// 包装一个已有的 [`reqwest::Client`]，没有 base url、重试和频率限制。
+    ///
+    /// `cookies` 应该是构造 `inner` 时使用的 cookie store，reqwest 没有办法从 client 中取出它；
+    /// 为 `None` 时 [`cookies`][`Client::cookies`] 也返回 `None`，但 `inner` 自己的 cookie 仍然生效
+    pub fn from_reqwest(inner: reqwest::Client, cookies: Option<Arc<dyn CookieStore>>) -> Self {
+        Self {
+            inner,
+            cookies,
+            base_urls: Default::default(),
+            retry_policy: None,
+            rate_limits: Default::default(),
+            wbi: Default::default(),
+        }
+    }
+
+    /// 底层的 [`reqwest::Client`]，不会改写请求地址
+    pub fn inner(&self) -> &reqwest::Client {
+        &self.inner
+    }
+
+    /// 使用的 cookie store，可以传给
+    /// [`LiveConnectionBuilder::cookies`][`crate::connection::LiveConnectionBuilder::cookies`]
+    pub fn cookies(&self) -> Option<Arc<dyn CookieStore>> {
+        self.cookies.clone()
+    }
+
+    /// 按照设置的 base url 改写地址，没有设置时原样返回
+    pub fn url(&self, url: &str) -> String {
+        match self.rewrite(url) {
+            Some(rewritten) => rewritten.into(),
+            None => url.to_string(),
+        }
+    }
+
+    /// 不需要改写时返回 None
+    fn rewrite(&self, url: &str) -> Option<Url> {
+        let parsed = Url::parse(url).ok()?;
+        let base = self
+            .base_urls
+            .get(&Host::from_domain(parsed.host_str()?)?)?;
+        let mut rewritten = base.clone();
+        rewritten.set_path(&format!(
+            "{}{}",
+            base.path().trim_end_matches('/'),
+            parsed.path()
+        ));
+        rewritten.set_query(parsed.query());
+        trace!("rewrite url {} to {}", url, rewritten);
+        Some(rewritten)
+    }
+
+    /// 请求发往的域名，`url` 可以是已经改写过的地址
+    fn host_of(&self, url: &Url) -> Option<Host> {
+        if let Some(host) = url.host_str().and_then(Host::from_domain) {
+            return Some(host);
+        }
+        // 多个域名指向同一个服务器时取路径最长的 base
+        self.base_urls
+            .iter()
+            .filter(|(_, base)| {
+                base.origin() == url.origin()
+                    && url.path().starts_with(base.path().trim_end_matches('/'))
+            })
+            .max_by_key(|(_, base)| base.path().len())
+            .map(|(host, _)| *host)
+    }
+
+    /// 设置的重试策略，见 [`ClientBuilder::retry_policy`]
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    /// 等待 `host` 的频率限制，没有设置时立即返回。
+    ///
+    /// [`execute`][`Client::execute`] 和 [`send`][`Client::send`] 会自动调用，
+    /// 只有绕过它们发送请求时才需要自己调用
+    pub async fn acquire(&self, host: Host) {
+        if let Some(bucket) = self.rate_limits.get(&host) {
+            bucket.acquire().await;
+        }
+    }
+
+    /// 缓存的 wbi key，clone 出来的 client 共享
+    pub(crate) fn wbi(&self) -> &WbiCache {
+        &self.wbi
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn put<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::PUT, url)
+    }
+
+    pub fn patch<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::PATCH, url)
+    }
+
+    pub fn delete<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::DELETE, url)
+    }
+
+    pub fn head<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::HEAD, url)
+    }
+
+    /// 同 [`reqwest::Client::request`]，按照 base url 改写地址
+    pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> RequestBuilder {
+        // 不能解析的地址原样交给 reqwest，由它在 send 时返回错误
+        match self.rewrite(url.as_str()) {
+            Some(rewritten) => self.inner.request(method, rewritten),
+            None => self.inner.request(method, url),
+        }
+    }
+
+    /// 同 [`reqwest::Client::execute`]，按照 base url 改写地址，发送前等待对应域名的频率限制
+    pub fn execute(
+        &self,
+        mut request: reqwest::Request,
+    ) -> impl Future<Output = reqwest::Result<Response>> {
+        if let Some(rewritten) = self.rewrite(request.url().as_str()) {
+            *request.url_mut() = rewritten;
+        }
+        // get 等方法构造的请求已经改写过，按照 base url 找回原来的域名
+        let host = self.host_of(request.url());
+        let client = self.clone();
+        async move {
+            if let Some(host) = host {
+                client.acquire(host).await;
+            }
+            client.inner.execute(request).await
+        }
+    }
+
+    /// 构造并发送 `request`，同 [`execute`][`Client::execute`]
+    pub fn send(&self, request: RequestBuilder) -> impl Future<Output = reqwest::Result<Response>> {
+        let request = request.build();
+        let client = self.clone();
+        async move { client.execute(request?).await }
+    }
+}
+
+/// [`Client`] 的构造器
+///
+/// # Example
+/// ```no_run
+/// use biliapi::connection::{Client, Host, RateLimit, RetryPolicy, UserAgent};
+/// use std::time::Duration;
+///
+/// let client = Client::builder()
+///     .user_agent(UserAgent::Chrome)
+///     .bilibili_headers()
+///     .timeout(Duration::from_secs(10))
+///     .proxy(reqwest::Proxy::all("socks5://127.0.0.1:1080").unwrap())
+///     .base_url(Host::ApiLive, "http://127.0.0.1:8080/".parse().unwrap())
+///     .retry_policy(RetryPolicy::new().max_attempts(5))
+///     .rate_limit(Host::Api, RateLimit::per_second(5))
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ClientBuilder {
+    user_agent: UserAgent,
+    referer: Option<String>,
+    origin: Option<String>,
+    proxies: Vec<reqwest::Proxy>,
+    no_proxy: bool,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    cookies: Option<Arc<dyn CookieStore>>,
+    base_urls: HashMap<Host, Url>,
+    retry_policy: Option<RetryPolicy>,
+    rate_limits: HashMap<Host, RateLimit>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            user_agent: UserAgent::default(),
+            referer: None,
+            origin: None,
+            proxies: vec![],
+            no_proxy: false,
+            timeout: None,
+            connect_timeout: None,
+            cookies: None,
+            base_urls: HashMap::new(),
+            retry_policy: None,
+            rate_limits: HashMap::new(),
+        }
+    }
+
+    /// 可以使用预设的 [`UserAgent`]，也可以直接传入字符串
+    pub fn user_agent(mut self, user_agent: impl Into<UserAgent>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// 每个请求都带上的 Referer
+    pub fn referer(mut self, referer: impl Into<String>) -> Self {
+        self.referer = Some(referer.into());
+        self
+    }
+
+    /// 每个请求都带上的 Origin
+    pub fn origin(mut self, origin: impl Into<String>) -> Self {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    /// 使用网页端的 Referer 和 Origin，部分接口会检查这两个请求头
+    pub fn bilibili_headers(self) -> Self {
+        self.referer("https://www.bilibili.com/")
+            .origin("https://www.bilibili.com")
+    }
+
+    /// 添加一个代理，可以多次调用
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// 不使用任何代理，包括系统代理
+    pub fn no_proxy(mut self) -> Self {
+        self.no_proxy = true;
+        self
+    }
+
+    /// 整个请求的超时时间
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 建立连接的超时时间
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// 使用自定义的 cookie store，如可以持久化的 `reqwest_cookie_store::CookieStoreMutex`。
+    ///
+    /// 默认使用一个空的 [`Jar`]
+    pub fn cookie_provider<C: CookieStore + 'static>(mut self, cookies: Arc<C>) -> Self {
+        self.cookies = Some(cookies);
+        self
+    }
+
+    /// 把某个域名的请求发到 `base` 上，如 `http://127.0.0.1:8080/live/`，
+    /// 请求的路径会拼接在 base 的路径之后。一般用于测试
+    pub fn base_url(mut self, host: Host, base: Url) -> Self {
+        self.base_urls.insert(host, base);
+        self
+    }
+
+    /// 请求失败时按照 `policy` 重试，默认不重试。
+    ///
+    /// 只对 [`Request::request_with_retry`][`crate::Request::request_with_retry`] 生效
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// 限制发往 `host` 的请求频率，同一个 client clone 出来的实例共享限制。
+    ///
+    /// 通过 [`Client::execute`] 和 [`Client::send`] 发送的请求都会等待，
+    /// 包括所有的 [`Request`][`crate::Request`] 和重试
+    pub fn rate_limit(mut self, host: Host, limit: RateLimit) -> Self {
+        self.rate_limits.insert(host, limit);
+        self
+    }
+
+    pub fn build(self) -> reqwest::Result<Client> {
+        trace!("user agent name: {}", self.user_agent.as_str());
+        let cookies = self
+            .cookies
+            .unwrap_or_else(|| Arc::new(Jar::default()) as Arc<dyn CookieStore>);
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (header::REFERER, self.referer),
+            (header::ORIGIN, self.origin),
+        ] {
+            if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+
+        let mut builder = reqwest::Client::builder()
+            .user_agent(self.user_agent.as_str())
+            .default_headers(headers)
+            .cookie_provider(Arc::new(SharedCookies(cookies.clone())));
+        for proxy in self.proxies {
+            builder = builder.proxy(proxy);
+        }
+        if self.no_proxy {
+            builder = builder.no_proxy();
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        Ok(Client {
+            inner: builder.build()?,
+            cookies: Some(cookies),
+            base_urls: Arc::new(self.base_urls),
+            retry_policy: self.retry_policy,
+            rate_limits: Arc::new(
+                self.rate_limits
+                    .into_iter()
+                    .map(|(host, limit)| (host, TokenBucket::new(limit)))
+                    .collect(),
+            ),
+            wbi: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url() {
+        let client = Client::builder()
+            .base_url(
+                Host::ApiLive,
+                Url::parse("http://127.0.0.1:8080/live/").unwrap(),
+            )
+            .base_url(Host::Api, Url::parse("http://127.0.0.1:8081").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.url("https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo?id=1"),
+            "http://127.0.0.1:8080/live/xlive/web-room/v1/index/getDanmuInfo?id=1"
+        );
+        assert_eq!(
+            client.url("https://api.bilibili.com/x/web-interface/nav"),
+            "http://127.0.0.1:8081/x/web-interface/nav"
+        );
+        // 没有设置的域名不变
+        assert_eq!(
+            client.url("https://passport.bilibili.com/qrcode/getLoginUrl"),
+            "https://passport.bilibili.com/qrcode/getLoginUrl"
+        );
+        let request = client
+            .get("https://api.bilibili.com/x/space/upstat")
+            .query(&[("mid", 1)])
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://127.0.0.1:8081/x/space/upstat?mid=1"
+        );
+
+        // 其他方法和已经构造好的请求也会改写
+        let url = Url::parse("https://api.bilibili.com/x/web-interface/nav").unwrap();
+        let request = client.head(url.clone()).build().unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://127.0.0.1:8081/x/web-interface/nav"
+        );
+        assert!(client.get("not a url").build().is_err());
+
+        // 改写之后的地址仍然能找回原来的域名
+        assert_eq!(
+            client
+                .host_of(&Url::parse("http://127.0.0.1:8080/live/room/v1/Room/room_init").unwrap()),
+            Some(Host::ApiLive)
+        );
+        assert_eq!(
+            client.host_of(&Url::parse("http://127.0.0.1:8081/x/web-interface/nav").unwrap()),
+            Some(Host::Api)
+        );
+        assert_eq!(
+            client
+                .host_of(&Url::parse("https://passport.bilibili.com/qrcode/getLoginUrl").unwrap()),
+            Some(Host::Passport)
+        );
+        assert_eq!(
+            client.host_of(&Url::parse("http://127.0.0.1:8082/").unwrap()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        let server = crate::testing::MockServer::with_fixtures().await.unwrap();
+        let client = server.client();
+        let url = Url::parse("https://api.bilibili.com/x/web-interface/nav").unwrap();
+        let response = client
+            .execute(reqwest::Request::new(Method::GET, url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(server.received()[0].path, "/x/web-interface/nav");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use crate::{connection::RateLimit, requests::UserInfo, Request};
+
+        let server = crate::testing::MockServer::with_fixtures().await.unwrap();
+        let client = server
+            .client_builder()
+            .rate_limit(Host::Api, RateLimit::new(1, Duration::from_millis(100)))
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        UserInfo::request(&client, 672328094).await.unwrap();
+        client
+            .send(client.get("https://api.bilibili.com/x/web-interface/nav"))
+            .await
+            .unwrap();
+        let url = Url::parse("https://api.bilibili.com/x/web-interface/nav").unwrap();
+        client
+            .execute(reqwest::Request::new(Method::GET, url))
+            .await
+            .unwrap();
+        // 三个请求共用一个令牌桶，后两个各等待 100ms
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(server.received().len(), 3);
+    }
+}
@@ -0,0 +1,371 @@
+//! 同时连接多个直播间
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use chrono::{DateTime, Local};
+use futures::{
+    stream::{AbortHandle, Abortable, BoxStream, SelectAll},
+    Stream, StreamExt,
+};
+
+use super::{
+    builder, Client, LiveConnectionBuilder, LiveMessage, ReconnectPolicy, ReconnectingConnection,
+};
+use crate::ws_protocol::Packet;
+
+/// 单个直播间的连接状态
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    /// 正在进行第一次连接
+    Connecting,
+    /// 已连接到 url
+    Connected { url: String },
+    /// 断线重连中
+    Reconnecting { attempt: u32, reason: String },
+    /// 重连次数超过上限，已经放弃
+    Failed { reason: String },
+}
+
+/// [`LiveHub`] 中单个直播间的状态
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomStatus {
+    pub state: ConnectionState,
+    /// 收到的包的数量
+    pub packets: u64,
+    /// 断线重连的次数
+    pub reconnects: u64,
+    /// 最后一次收到包的时间
+    pub last_packet_at: Option<DateTime<Local>>,
+}
+
+impl RoomStatus {
+    fn new() -> Self {
+        Self {
+            state: ConnectionState::Connecting,
+            packets: 0,
+            reconnects: 0,
+            last_packet_at: None,
+        }
+    }
+}
+
+struct HubRoom {
+    /// 失败之后置为 None，可以重新添加
+    abort: Option<AbortHandle>,
+    status: RoomStatus,
+}
+
+/// 多直播间连接，每个直播间使用一个 [`ReconnectingConnection`]，所有的包合并为一个 [`Stream`]
+///
+/// stream 的每一项都带上了对应的（长）房号；某个直播间重连失败时会返回该直播间的错误，其他直播间不受影响。
+/// 每次 poll 只会 poll 收到了通知的直播间，适合同时连接大量直播间。
+///
+/// # Example
+/// ```no_run
+/// # use biliapi::connection::LiveHub;
+/// # tokio_test::block_on(async {
+/// use futures::StreamExt;
+/// let client = biliapi::connection::Client::new().unwrap();
+/// let mut hub = LiveHub::new(client);
+/// // 短号会转换为长房号 5440
+/// hub.add_room(1).await.unwrap();
+/// hub.add_room(21133).await.unwrap();
+/// while let Some((room_id, packet)) = hub.next().await {
+///     match packet {
+///         Ok(packet) => {}
+///         Err(e) => println!("room {} failed: {}", room_id, e),
+///     }
+/// }
+/// # });
+/// ```
+pub struct LiveHub {
+    client: Client,
+    policy: ReconnectPolicy,
+    builder: LiveConnectionBuilder,
+    /// 以长房号为 key
+    rooms: BTreeMap<u64, HubRoom>,
+    /// 添加时使用的房间号（可能是短号）到长房号
+    aliases: HashMap<u64, u64>,
+    streams: SelectAll<BoxStream<'static, (u64, crate::Result<LiveMessage>)>>,
+    waker: Option<Waker>,
+}
+
+impl LiveHub {
+    pub fn new(client: Client) -> Self {
+        Self::with_config(
+            client,
+            ReconnectPolicy::default(),
+            LiveConnectionBuilder::new(),
+        )
+    }
+
+    /// 所有直播间都使用 `policy` 重连、使用 `builder` 建立连接
+    pub fn with_config(
+        client: Client,
+        policy: ReconnectPolicy,
+        builder: LiveConnectionBuilder,
+    ) -> Self {
+        Self {
+            client,
+            policy,
+            builder,
+            rooms: BTreeMap::new(),
+            aliases: HashMap::new(),
+            streams: SelectAll::new(),
+            waker: None,
+        }
+    }
+
+    /// 添加一个直播间，短号会先转换为长房号。
+    ///
+    /// 已经存在（包括短号和长房号指向同一个直播间）时返回 false；已经失败的直播间会重新连接
+    pub async fn add_room(&mut self, room_id: u64) -> crate::Result<bool> {
+        let resolved = match self.aliases.get(&room_id) {
+            Some(resolved) => *resolved,
+            None => builder::resolve_room_id(&self.client, room_id).await?,
+        };
+        self.aliases.insert(room_id, resolved);
+        self.aliases.insert(resolved, resolved);
+        Ok(self.add_resolved(resolved))
+    }
+
+    fn add_resolved(&mut self, room_id: u64) -> bool {
+        if self.rooms.get(&room_id).is_some_and(|r| r.abort.is_some()) {
+            return false;
+        }
+        let connection = ReconnectingConnection::with_resolved_room(
+            self.client.clone(),
+            room_id,
+            self.policy.clone(),
+            self.builder.clone(),
+        );
+        let (abort, registration) = AbortHandle::new_pair();
+        let stream = Abortable::new(connection, registration).map(move |msg| (room_id, msg));
+        self.streams.push(stream.boxed());
+        self.rooms.insert(
+            room_id,
+            HubRoom {
+                abort: Some(abort),
+                status: RoomStatus::new(),
+            },
+        );
+        // 新的连接还没有被 poll 过
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    fn resolve(&self, room_id: u64) -> u64 {
+        self.aliases.get(&room_id).copied().unwrap_or(room_id)
+    }
+
+    /// 移除一个直播间并断开连接（在下一次 poll 时），可以使用添加时的房间号，不存在时返回 false
+    pub fn remove_room(&mut self, room_id: u64) -> bool {
+        let resolved = self.resolve(room_id);
+        match self.rooms.remove(&resolved) {
+            Some(room) => {
+                if let Some(abort) = room.abort {
+                    abort.abort();
+                }
+                self.aliases.retain(|_, r| *r != resolved);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 是否已经添加了这个直播间，包括已经失败的
+    pub fn contains(&self, room_id: u64) -> bool {
+        self.rooms.contains_key(&self.resolve(room_id))
+    }
+
+    /// 所有直播间的长房号，按房号排序
+    pub fn rooms(&self) -> impl Iterator<Item = u64> + '_ {
+        self.rooms.keys().copied()
+    }
+
+    /// 查询某个直播间的状态，可以使用添加时的房间号
+    pub fn status(&self, room_id: u64) -> Option<&RoomStatus> {
+        self.rooms.get(&self.resolve(room_id)).map(|r| &r.status)
+    }
+
+    /// 所有直播间的状态
+    pub fn statuses(&self) -> impl Iterator<Item = (u64, &RoomStatus)> + '_ {
+        self.rooms.iter().map(|(room_id, r)| (*room_id, &r.status))
+    }
+}
+
+impl Stream for LiveHub {
+    type Item = (u64, crate::Result<Packet>);
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.waker = Some(cx.waker().clone());
+
+        loop {
+            let (room_id, msg) = match this.streams.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => item,
+                // 没有直播间时等待 add_room
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            };
+            // 已经移除的直播间
+            let room = match this.rooms.get_mut(&room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            match msg {
+                Ok(LiveMessage::Packet(packet)) => {
+                    room.status.packets += 1;
+                    room.status.last_packet_at = Some(packet.time);
+                    return Poll::Ready(Some((room_id, Ok(packet))));
+                }
+                Ok(LiveMessage::Connected { url }) => {
+                    room.status.state = ConnectionState::Connected { url };
+                }
+                Ok(LiveMessage::Reconnected { url }) => {
+                    room.status.reconnects += 1;
+                    room.status.state = ConnectionState::Connected { url };
+                }
+                Ok(LiveMessage::Reconnecting {
+                    attempt, reason, ..
+                }) => {
+                    room.status.state = ConnectionState::Reconnecting { attempt, reason };
+                }
+                Err(e) => {
+                    room.status.state = ConnectionState::Failed {
+                        reason: e.to_string(),
+                    };
+                    room.abort = None;
+                    return Poll::Ready(Some((room_id, Err(e))));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{Host, Transport},
+        testing::{fixtures, LiveScript, Mock, MockLiveServer, MockResponse, MockServer},
+        ws_protocol::{KnownOperation, Operation},
+    };
+    use std::time::Duration;
+
+    const ROOM_INIT: &str = "/xlive/web-room/v1/index/getInfoByRoom";
+    const DANMU_INFO: &str = "/xlive/web-room/v1/index/getDanmuInfo";
+
+    /// 让 `room_id` 解析为自己
+    fn mount_room(http: &MockServer, room_id: u64) {
+        let mut info: serde_json::Value = serde_json::from_str(fixtures::ROOM_INFO).unwrap();
+        info["data"]["room_info"]["room_id"] = room_id.into();
+        info["data"]["room_info"]["short_id"] = 0.into();
+        http.mount(
+            Mock::get(Host::ApiLive, ROOM_INIT)
+                .query("room_id", room_id)
+                .respond(MockResponse::json(info.to_string())),
+        );
+    }
+
+    fn danmaku(text: &str) -> Packet {
+        Packet::new(Operation::Known(KnownOperation::SendMsgReply), text, 0)
+    }
+
+    fn hub(http: &MockServer) -> LiveHub {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(0),
+            ..Default::default()
+        };
+        let builder = LiveConnectionBuilder::new().transport(Transport::Tcp);
+        LiveHub::with_config(http.client(), policy, builder)
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_rooms() {
+        let http = MockServer::with_fixtures().await.unwrap();
+        mount_room(&http, 5440);
+        let mut hub = hub(&http);
+        // 646 是 21133 的短号
+        assert!(hub.add_room(646).await.unwrap());
+        assert!(!hub.add_room(21133).await.unwrap());
+        assert!(hub.add_room(5440).await.unwrap());
+        assert!(!hub.add_room(646).await.unwrap());
+        assert_eq!(hub.rooms().collect::<Vec<_>>(), vec![5440, 21133]);
+        assert_eq!(hub.status(646).unwrap().state, ConnectionState::Connecting);
+
+        assert!(hub.remove_room(646));
+        assert!(!hub.remove_room(21133));
+        assert!(hub.status(21133).is_none());
+        assert_eq!(hub.rooms().collect::<Vec<_>>(), vec![5440]);
+    }
+
+    #[tokio::test]
+    async fn test_merged_stream() {
+        let http = MockServer::with_fixtures().await.unwrap();
+        let live_a = MockLiveServer::start().await.unwrap();
+        live_a.push_script(LiveScript::new().send(&danmaku("a1")).send(&danmaku("a2")));
+        let live_b = MockLiveServer::start().await.unwrap();
+        live_b.push_script(LiveScript::new().send(&danmaku("b1")));
+        mount_room(&http, 5440);
+        mount_room(&http, 7777);
+        for (room_id, response) in [
+            (21133, live_a.danmu_info("a")),
+            (5440, live_b.danmu_info("b")),
+            (7777, MockResponse::bili_error(-400, "请求错误")),
+        ] {
+            http.mount(
+                Mock::get(Host::ApiLive, DANMU_INFO)
+                    .query("id", room_id)
+                    .respond(response),
+            );
+        }
+
+        let mut hub = hub(&http);
+        for room_id in [646, 5440, 7777] {
+            assert!(hub.add_room(room_id).await.unwrap());
+        }
+        let mut received = vec![];
+        let mut failed = vec![];
+        while received.len() < 3 || failed.is_empty() {
+            let (room_id, result) = tokio::time::timeout(Duration::from_secs(5), hub.next())
+                .await
+                .unwrap()
+                .unwrap();
+            match result {
+                Ok(packet) if packet.operation == danmaku("").operation => {
+                    assert_eq!(packet.room_id, room_id);
+                    received.push((room_id, packet.body));
+                }
+                Ok(_) => {}
+                Err(_) => failed.push(room_id),
+            }
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                (5440, "b1".to_string()),
+                (21133, "a1".to_string()),
+                (21133, "a2".to_string()),
+            ]
+        );
+        assert_eq!(failed, [7777]);
+        assert!(matches!(
+            hub.status(646).unwrap().state,
+            ConnectionState::Connected { .. }
+        ));
+        assert!(matches!(
+            hub.status(7777).unwrap().state,
+            ConnectionState::Failed { .. }
+        ));
+        assert_eq!(hub.status(21133).unwrap().packets, 2);
+        // 失败的直播间可以重新添加
+        assert!(hub.add_room(7777).await.unwrap());
+        assert_eq!(hub.status(7777).unwrap().state, ConnectionState::Connecting);
+    }
+}
@@ -0,0 +1,340 @@
+//! 自动重连的直播间连接
+//!
+//! 断线后会重新获取 [`DanmuInfo`]（刷新 token），轮流尝试所有的弹幕服务器，并且使用带抖动的指数退避
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_tungstenite::tungstenite::Error as WsError;
+use futures::{stream::BoxStream, Stream, StreamExt};
+
+use super::{builder, Client, LiveConnection, LiveConnectionBuilder};
+use crate::{requests::DanmuInfo, ws_protocol, Request};
+
+/// 重连策略，使用带随机抖动的指数退避
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// 第一次重连前等待的时间
+    pub initial_backoff: Duration,
+    /// 等待时间的上限
+    pub max_backoff: Duration,
+    /// 每次失败后等待时间乘以的倍数
+    pub multiplier: f64,
+    /// 连续失败多少次之后放弃，None 表示一直重试
+    pub max_attempts: Option<u32>,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_attempts: None,
+        }
+    }
+}
+impl ReconnectPolicy {
+    /// 第 `attempt` 次（从 1 开始）重连前需要等待的时间
+    ///
+    /// 结果在 `[base / 2, base]` 之间，其中 base 为指数退避的时间
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let secs = (self.initial_backoff.as_secs_f64() * exp).min(self.max_backoff.as_secs_f64());
+        let half = Duration::from_secs_f64(secs.max(0.0)) / 2;
+        half + half.mul_f64(jitter())
+    }
+}
+
+/// `[0, 1)` 之间的一个随机数，只用来打散重连时间，不需要很随机
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    nanos as f64 / 1e9
+}
+
+/// [`ReconnectingConnection`] 产生的消息
+#[derive(Debug)]
+pub enum LiveMessage {
+    /// 收到的数据包
+    Packet(ws_protocol::Packet),
+    /// 第一次连接成功
+    Connected { url: String },
+    /// 连接断开或者连接失败，将在 `delay` 之后进行第 `attempt` 次重连
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+        reason: String,
+    },
+    /// 断线后重新连接成功
+    Reconnected { url: String },
+}
+impl LiveMessage {
+    /// 如果是数据包则返回数据包
+    pub fn into_packet(self) -> Option<ws_protocol::Packet> {
+        match self {
+            Self::Packet(packet) => Some(packet),
+            _ => None,
+        }
+    }
+}
+
+/// 会自动重连的直播间连接，实现了 [`Stream`]
+///
+/// 只有在连续失败次数超过 [`ReconnectPolicy::max_attempts`] 时才会返回错误并结束
+///
+/// # Example
+/// ```no_run
+/// # use biliapi::connection::{ReconnectingConnection, ReconnectPolicy, LiveMessage};
+/// # tokio_test::block_on(async {
+/// use futures::StreamExt;
+/// let client = biliapi::connection::Client::new().unwrap();
+/// // 短号会在第一次连接时转换为长房号
+/// let mut con = ReconnectingConnection::new(client, 1, ReconnectPolicy::default());
+/// while let Some(msg) = con.next().await {
+///     match msg.unwrap() {
+///         LiveMessage::Packet(packet) => {}
+///         other => println!("{:?}", other),
+///     }
+/// }
+/// # });
+/// ```
+pub struct ReconnectingConnection {
+    inner: BoxStream<'static, crate::Result<LiveMessage>>,
+}
+
+impl ReconnectingConnection {
+    /// 创建一个连接，连接会在第一次 poll 的时候建立
+    pub fn new(client: Client, room_id: u64, policy: ReconnectPolicy) -> Self {
+        Self::with_builder(client, room_id, policy, LiveConnectionBuilder::new())
+    }
+
+    /// 同 [`new`][`ReconnectingConnection::new`]，但是每次连接都使用 `builder` 的配置
+    pub fn with_builder(
+        client: Client,
+        room_id: u64,
+        policy: ReconnectPolicy,
+        builder: LiveConnectionBuilder,
+    ) -> Self {
+        Self::create(client, room_id, policy, builder, false)
+    }
+
+    /// `room_id` 已经是长房号，连接时不再转换
+    pub(crate) fn with_resolved_room(
+        client: Client,
+        room_id: u64,
+        policy: ReconnectPolicy,
+        builder: LiveConnectionBuilder,
+    ) -> Self {
+        Self::create(client, room_id, policy, builder, true)
+    }
+
+    fn create(
+        client: Client,
+        room_id: u64,
+        policy: ReconnectPolicy,
+        builder: LiveConnectionBuilder,
+        resolved: bool,
+    ) -> Self {
+        let state = State {
+            client,
+            room_id,
+            builder,
+            policy,
+            resolved,
+            connection: None,
+            server_index: 0,
+            failures: 0,
+            delay: None,
+            ever_connected: false,
+            finished: false,
+        };
+        Self {
+            inner: futures::stream::unfold(state, State::next_message).boxed(),
+        }
+    }
+}
+
+impl Stream for ReconnectingConnection {
+    type Item = crate::Result<LiveMessage>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+struct State {
+    client: Client,
+    room_id: u64,
+    builder: LiveConnectionBuilder,
+    policy: ReconnectPolicy,
+    /// room_id 是否已经转换为长房号
+    resolved: bool,
+    connection: Option<LiveConnection>,
+    /// 下一次连接使用的服务器，连接失败或者断开后轮换
+    server_index: usize,
+    /// 连续失败的次数
+    failures: u32,
+    /// 下一次连接前需要等待的时间
+    delay: Option<Duration>,
+    ever_connected: bool,
+    finished: bool,
+}
+
+impl State {
+    async fn next_message(mut self) -> Option<(crate::Result<LiveMessage>, Self)> {
+        if self.finished {
+            return None;
+        }
+        let error = match self.connection.as_mut() {
+            Some(connection) => match connection.next().await {
+                Some(Ok(packet)) => {
+                    self.failures = 0;
+                    return Some((Ok(LiveMessage::Packet(packet)), self));
+                }
+                Some(Err(e)) => e,
+                None => WsError::ConnectionClosed.into(),
+            },
+            None => {
+                if let Some(delay) = self.delay.take() {
+                    tokio::time::sleep(delay).await;
+                }
+                match self.connect().await {
+                    Ok(url) => {
+                        let message = if self.ever_connected {
+                            info!("room {} reconnected to {}", self.room_id, url);
+                            LiveMessage::Reconnected { url }
+                        } else {
+                            self.ever_connected = true;
+                            LiveMessage::Connected { url }
+                        };
+                        return Some((Ok(message), self));
+                    }
+                    Err(e) => e,
+                }
+            }
+        };
+        let message = self.on_failure(error);
+        Some((message, self))
+    }
+
+    /// 重新获取 token 和服务器列表，并连接到下一个服务器，第一次连接时把短号转换为长房号
+    async fn connect(&mut self) -> crate::Result<String> {
+        if !self.resolved {
+            self.room_id = builder::resolve_room_id(&self.client, self.room_id).await?;
+            self.resolved = true;
+        }
+        let danmu_info = DanmuInfo::request(&self.client, self.room_id).await?;
+        if danmu_info.servers.is_empty() {
+            return Err(crate::Error::DataNotFound);
+        }
+        let server = &danmu_info.servers[self.server_index % danmu_info.servers.len()];
+        let url = self.builder.server_url(server);
+        debug!("room {} connecting to {}", self.room_id, url);
+        let connection = self
+            .builder
+            .clone()
+            .connect(&url, self.room_id, danmu_info.token)
+            .await?;
+        self.connection = Some(connection);
+        Ok(url)
+    }
+
+    fn on_failure(&mut self, error: crate::Error) -> crate::Result<LiveMessage> {
+        self.connection = None;
+        self.server_index += 1;
+        self.failures += 1;
+        if matches!(self.policy.max_attempts, Some(max) if self.failures > max) {
+            warn!(
+                "room {} failed {} times in a row, give up: {}",
+                self.room_id, self.failures, error
+            );
+            self.finished = true;
+            return Err(error);
+        }
+        let delay = self.policy.backoff(self.failures);
+        warn!(
+            "room {} disconnected: {}, reconnecting in {:?}",
+            self.room_id, error, delay
+        );
+        self.delay = Some(delay);
+        Ok(LiveMessage::Reconnecting {
+            attempt: self.failures,
+            delay,
+            reason: error.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{Host, Transport},
+        testing::{LiveScript, Mock, MockLiveServer, MockResponse, MockServer},
+        ws_protocol::{KnownOperation, Operation, Packet},
+    };
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            max_attempts: None,
+        };
+        for (attempt, base) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (100, 10)] {
+            let backoff = policy.backoff(attempt);
+            let base = Duration::from_secs(base);
+            assert!(backoff >= base / 2 && backoff <= base, "{:?}", backoff);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_server_on_disconnect() {
+        let danmaku = |text| Packet::new(Operation::Known(KnownOperation::SendMsgReply), text, 0);
+        let first = MockLiveServer::start().await.unwrap();
+        first.push_script(LiveScript::new().send(&danmaku("first")).drop_connection());
+        let second = MockLiveServer::start().await.unwrap();
+        second.push_script(LiveScript::new().send(&danmaku("second")));
+        let host = |server: &MockLiveServer| {
+            let addr = server.addr();
+            serde_json::json!({
+                "host": addr.ip().to_string(),
+                "port": addr.port(),
+                "wss_port": addr.port(),
+                "ws_port": addr.port(),
+            })
+        };
+        let http = MockServer::with_fixtures().await.unwrap();
+        http.mount(
+            Mock::get(Host::ApiLive, "/xlive/web-room/v1/index/getDanmuInfo").respond(
+                MockResponse::data(serde_json::json!({
+                    "token": "token",
+                    "host_list": [host(&first), host(&second)],
+                })),
+            ),
+        );
+
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let builder = LiveConnection::builder().transport(Transport::Tcp);
+        let mut con = ReconnectingConnection::with_builder(http.client(), 5440, policy, builder);
+        loop {
+            match con.next().await.unwrap().unwrap() {
+                LiveMessage::Reconnected { url } => {
+                    assert_eq!(url, second.tcp_url());
+                    break;
+                }
+                LiveMessage::Packet(packet) => assert_ne!(packet.body, "second"),
+                _ => {}
+            }
+        }
+        assert_eq!((first.connections(), second.connections()), (1, 1));
+    }
+}
@@ -0,0 +1,386 @@
+//! http 请求的重试策略和频率限制
+
+use std::{fmt, future::Future, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use super::{Client, Host};
+use crate::{Error, Result};
+
+/// 请求失败时的重试策略，通过 [`ClientBuilder::retry_policy`][`super::ClientBuilder::retry_policy`]
+/// 设置，只对 [`Request::request_with_retry`][`crate::Request::request_with_retry`] 生效。
+///
+/// 第 n 次重试之前等待 `initial_backoff * multiplier^(n-1)`，不超过 `max_backoff`
+///
+/// # Example
+/// ```
+/// use biliapi::connection::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::new()
+///     .max_attempts(5)
+///     .initial_backoff(Duration::from_secs(1))
+///     .max_backoff(Duration::from_secs(30));
+/// assert_eq!(policy.backoff(1), Duration::from_secs(1));
+/// assert_eq!(policy.backoff(3), Duration::from_secs(4));
+/// ```
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    retryable: fn(&Error) -> bool,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    /// 默认最多请求 3 次，从 500ms 开始每次翻倍，最长等待 10s
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            retryable: Error::is_retryable,
+        }
+    }
+
+    /// 总的请求次数上限（包括第一次），最小为 1
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// 每次重试等待时间的倍数，小于 1 时按 1 处理
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// 自定义哪些错误需要重试，默认为 [`Error::is_retryable`]，如只重试 [`Error::is_rate_limited`]
+    pub fn retry_if(mut self, retryable: fn(&Error) -> bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    /// 第 `retry` 次重试（从 1 开始）之前需要等待的时间
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1) as i32);
+        let secs = self.initial_backoff.as_secs_f64() * factor;
+        if secs.is_finite() && secs < self.max_backoff.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            self.max_backoff
+        }
+    }
+
+    /// 第 `attempt` 次请求失败之后是否应该重试
+    pub fn should_retry(&self, attempt: u32, error: &Error) -> bool {
+        attempt < self.max_attempts && (self.retryable)(error)
+    }
+}
+
+/// 令牌桶频率限制，通过 [`ClientBuilder::rate_limit`][`super::ClientBuilder::rate_limit`]
+/// 对每个 [`Host`] 分别设置。
+///
+/// 和 [`RetryPolicy`] 不同，它对所有通过 [`Client::execute`][`super::Client::execute`] 或
+/// [`Client::send`][`super::Client::send`] 发出的请求生效，包括每一次重试；
+/// 只有直接调用 [`reqwest::RequestBuilder::send`] 的请求不会等待
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    burst: u32,
+    per_second: f64,
+}
+
+impl RateLimit {
+    /// 每 `period` 最多 `requests` 个请求，允许一次性用完
+    pub fn new(requests: u32, period: Duration) -> Self {
+        let requests = requests.max(1);
+        Self {
+            burst: requests,
+            per_second: requests as f64 / period.as_secs_f64().max(f64::EPSILON),
+        }
+    }
+
+    /// 每秒最多 `requests` 个请求
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    /// 桶的容量，即最多允许连续发出多少个请求
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.burst as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// 取出一个令牌，返回还需要等待的时间
+    fn try_acquire(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Some(Duration::from_secs_f64(missing / self.limit.per_second))
+        }
+    }
+
+    pub(crate) async fn acquire(&self) {
+        while let Some(wait) = self.try_acquire() {
+            trace!("rate limited, wait for {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 按照 client 的重试策略执行 `f`，频率限制由 `f` 发出的请求自己等待
+pub(crate) async fn with_retry<T, F, Fut>(client: &Client, host: Host, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match f().await {
+            Ok(t) => return Ok(t),
+            Err(e) => e,
+        };
+        match client.retry_policy() {
+            Some(policy) if policy.should_retry(attempt, &error) => {
+                let backoff = policy.backoff(attempt);
+                warn!(
+                    "request to {} failed (attempt {}): {}, retry in {:?}",
+                    host.domain(),
+                    attempt,
+                    error,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn rate_limited() -> Error {
+        Error::BiliCustom {
+            code: crate::BiliErrorCode::RequestBlocked,
+            message: "请求被拦截".to_string(),
+            url: None,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_secs(1))
+            .multiplier(3.0);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(300));
+        assert_eq!(policy.backoff(3), Duration::from_millis(900));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::new().max_attempts(2);
+        assert!(policy.should_retry(1, &rate_limited()));
+        assert!(!policy.should_retry(2, &rate_limited()));
+        assert!(!policy.should_retry(1, &Error::DataNotFound));
+        assert!(policy.should_retry(
+            1,
+            &Error::StatusCode(reqwest::StatusCode::TOO_MANY_REQUESTS)
+        ));
+        assert!(!policy.should_retry(1, &Error::StatusCode(reqwest::StatusCode::NOT_FOUND)));
+
+        let policy = policy.retry_if(|e| matches!(e, Error::DataNotFound));
+        assert!(policy.should_retry(1, &Error::DataNotFound));
+        assert!(!policy.should_retry(1, &rate_limited()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(RateLimit::per_second(2));
+        let start = Instant::now();
+        for _ in 0..5 {
+            bucket.acquire().await;
+        }
+        // 前两个立即通过，之后每 500ms 一个
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_retry() {
+        let client = Client::builder()
+            .retry_policy(
+                RetryPolicy::new()
+                    .max_attempts(4)
+                    .initial_backoff(Duration::from_secs(1)),
+            )
+            .build()
+            .unwrap();
+        let calls = AtomicU32::new(0);
+        let start = Instant::now();
+        let result = with_retry(&client, Host::Api, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(rate_limited()),
+                n => Ok(n),
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, 2);
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2));
+
+        // 不可重试的错误直接返回
+        calls.store(0, Ordering::SeqCst);
+        let result: Result<()> = with_retry(&client, Host::Api, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(Error::DataNotFound)
+        })
+        .await;
+        assert!(matches!(result, Err(Error::DataNotFound)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 达到次数上限
+        calls.store(0, Ordering::SeqCst);
+        let result: Result<()> = with_retry(&client, Host::Api, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(rate_limited())
+        })
+        .await;
+        assert!(matches!(
+            result,
+            Err(Error::BiliCustom {
+                code: crate::BiliErrorCode::RequestBlocked,
+                ..
+            })
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_request_with_retry() {
+        use crate::{
+            requests::UserInfo,
+            testing::{Mock, MockResponse, MockServer},
+            Request,
+        };
+
+        let server = MockServer::with_fixtures().await.unwrap();
+        server.mount(
+            Mock::get(Host::Api, "/x/web-interface/card")
+                .respond_once(MockResponse::status(
+                    reqwest::StatusCode::PRECONDITION_FAILED,
+                ))
+                .respond_once(MockResponse::bili_error(-799, "请求过于频繁，请稍后再试")),
+        );
+        let client = server
+            .client_builder()
+            .retry_policy(RetryPolicy::new().initial_backoff(Duration::from_millis(10)))
+            .build()
+            .unwrap();
+        let info = UserInfo::request_with_retry(&client, 672328094)
+            .await
+            .unwrap();
+        assert_eq!(info.name, "嘉然今天吃什么");
+        assert_eq!(server.received().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_without_policy() {
+        let client = Client::builder()
+            .rate_limit(Host::ApiLive, RateLimit::per_second(1))
+            .build()
+            .unwrap();
+        let calls = AtomicU32::new(0);
+        let start = Instant::now();
+        for _ in 0..3 {
+            let result: Result<()> = with_retry(&client, Host::ApiLive, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(rate_limited())
+            })
+            .await;
+            assert!(result.is_err());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // 频率限制由发出的请求等待，with_retry 自己不等待
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            client.acquire(Host::ApiLive).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        // 其他域名不受限制
+        let start = Instant::now();
+        for _ in 0..3 {
+            client.acquire(Host::Api).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
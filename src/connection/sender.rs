@@ -0,0 +1,132 @@
+//! 直播间连接的发送端
+
+use futures::lock::Mutex;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use super::transport::Writer;
+use crate::ws_protocol::Packet;
+
+/// [`LiveConnection`][`super::LiveConnection`] 的发送端，可以 clone 之后在其他任务中使用
+///
+/// 发送是直接写入连接的，不需要同时 poll [`LiveConnection`][`super::LiveConnection`]
+///
+/// # Example
+/// ```no_run
+/// # use biliapi::connection::LiveConnection;
+/// # use biliapi::ws_protocol::{KnownOperation, Operation, Packet};
+/// # let (url, room_id, token) = ("", 1, "".to_string());
+/// # tokio_test::block_on(async {
+/// let con = LiveConnection::new(url, room_id, token).await.unwrap();
+/// let sender = con.sender();
+/// let packet = Packet::new(Operation::Known(KnownOperation::Register), "{}", room_id);
+/// sender.send(packet).await.unwrap();
+/// sender.close().await.unwrap();
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct LiveSender {
+    writer: Arc<Mutex<Writer>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl LiveSender {
+    pub(super) fn new(writer: Writer) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 发送一个 packet，编码方式见 [`Packet::to_bytes`]
+    ///
+    /// 连接已经关闭时返回 [`LiveClosed`][`crate::Error::LiveClosed`]
+    pub async fn send(&self, packet: Packet) -> crate::Result<()> {
+        let mut writer = self.writer.lock().await;
+        // 拿到锁之后再检查，避免和 close 交错
+        if self.is_closed() {
+            return Err(crate::Error::LiveClosed);
+        }
+        writer.send(packet).await
+    }
+
+    /// 主动关闭连接：websocket 发送 close 帧，TCP 关闭写入端。
+    ///
+    /// 之后心跳停止，[`LiveConnection`][`super::LiveConnection`] 会在服务器断开后结束，
+    /// 不再返回超时错误。重复调用不会报错
+    pub async fn close(&self) -> crate::Result<()> {
+        let mut writer = self.writer.lock().await;
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        debug!("closing live connection");
+        writer.close().await
+    }
+
+    /// 是否已经调用过 [`close`][`LiveSender::close`]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connection::LiveConnection,
+        ws_protocol::{KnownOperation, Operation, Packet, PacketDecoder},
+    };
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn test_send_and_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let reply = Packet::new(
+                Operation::Known(KnownOperation::AuthReply),
+                r#"{"code":0}"#,
+                1,
+            );
+            socket.write_all(&reply.to_bytes()).await.unwrap();
+            // 读到客户端关闭为止
+            let mut received = vec![];
+            socket.read_to_end(&mut received).await.unwrap();
+            PacketDecoder::new(1).decode_all(&received).unwrap()
+        });
+
+        let mut con = LiveConnection::builder()
+            .connect(&url, 1, "token".to_string())
+            .await
+            .unwrap();
+        let sender = con.sender();
+        let register = Packet::new(Operation::Known(KnownOperation::Register), "{}", 1);
+        sender.clone().send(register).await.unwrap();
+        sender.close().await.unwrap();
+        sender.close().await.unwrap();
+        assert!(sender
+            .send(Packet::heartbeat())
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("closed"));
+
+        let received = server.await.unwrap();
+        let operations: Vec<_> = received.iter().map(|p| p.operation).collect();
+        assert_eq!(
+            operations,
+            vec![
+                Operation::Known(KnownOperation::Auth),
+                Operation::Known(KnownOperation::Register)
+            ]
+        );
+        // 服务器断开之后连接正常结束
+        assert!(con.next().await.is_none());
+    }
+}
@@ -0,0 +1,105 @@
+//! 直播间连接的传输层，websocket 和原始 TCP 使用相同的二进制协议
+
+use async_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{header::COOKIE, HeaderValue},
+    Message as WsMessage,
+};
+use futures::{
+    stream::{BoxStream, SplitSink},
+    SinkExt, StreamExt,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+use crate::{
+    requests::DanmuServer,
+    ws_protocol::{Packet, PacketDecoder},
+};
+use tokio_util::codec::FramedRead;
+
+type WebSocketStream = async_tungstenite::WebSocketStream<async_tungstenite::tokio::ConnectStream>;
+
+/// 每次读到的数据解析出的 packet，一次可能解析出多个，也可能一个都没有
+pub(super) type PacketStream = BoxStream<'static, crate::Result<Vec<Packet>>>;
+
+/// 连接弹幕服务器使用的传输协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// wss 协议，默认
+    #[default]
+    WebSocket,
+    /// 原始 TCP，使用 [`DanmuServer::port`]，开销更小
+    Tcp,
+}
+impl Transport {
+    /// 该协议下服务器对应的 url
+    pub fn url(&self, server: &DanmuServer) -> String {
+        match self {
+            Self::WebSocket => server.url(),
+            Self::Tcp => server.tcp_url(),
+        }
+    }
+}
+
+/// 连接的写入端
+pub(super) enum Writer {
+    WebSocket(SplitSink<WebSocketStream, WsMessage>),
+    Tcp(OwnedWriteHalf),
+}
+impl Writer {
+    pub async fn send(&mut self, packet: Packet) -> crate::Result<()> {
+        match self {
+            Self::WebSocket(sink) => sink.send(packet.into()).await?,
+            Self::Tcp(write) => write.write_all(&packet.to_bytes()).await?,
+        }
+        Ok(())
+    }
+
+    /// websocket 发送 close 帧，TCP 关闭写入端
+    pub async fn close(&mut self) -> crate::Result<()> {
+        match self {
+            Self::WebSocket(sink) => sink.close().await?,
+            Self::Tcp(write) => write.shutdown().await?,
+        }
+        Ok(())
+    }
+}
+
+/// 根据 url 的协议建立连接：`tcp://host:port` 使用原始 TCP，其他的使用 websocket
+pub(super) async fn connect(
+    url: &str,
+    decoder: PacketDecoder,
+    cookie: Option<HeaderValue>,
+) -> crate::Result<(Writer, PacketStream)> {
+    if let Some(addr) = url.strip_prefix("tcp://") {
+        let (read, write) = TcpStream::connect(addr).await?.into_split();
+        return Ok((Writer::Tcp(write), tcp_packets(read, decoder)));
+    }
+
+    let mut request = url.into_client_request()?;
+    if let Some(cookie) = cookie {
+        request.headers_mut().insert(COOKIE, cookie);
+    }
+    let (websocket, _http) = async_tungstenite::tokio::connect_async(request).await?;
+    let (write, read) = websocket.split();
+    let read = read
+        .map(move |ws_message| match ws_message? {
+            WsMessage::Binary(bytes) => Ok(decoder.decode_all(&bytes)?),
+            ws_message => Ok(Packet::from_ws_message(ws_message, decoder.room_id())?),
+        })
+        .boxed();
+    Ok((Writer::WebSocket(write), read))
+}
+
+/// TCP 是字节流，需要按照 header 中的长度把数据重新组装成完整的帧
+fn tcp_packets(read: OwnedReadHalf, decoder: PacketDecoder) -> PacketStream {
+    FramedRead::new(read, decoder)
+        .map(|packets| Ok(packets?))
+        .boxed()
+}
@@ -12,6 +12,15 @@
 //! # live
 //! 启用 b 站直播相关 api，默认关闭
 //!
+//! # zstd
+//! 录制时可以使用 zstd 压缩分段，默认关闭
+//!
+//! # testing
+//! 启用 [`testing`] 模块，提供本地的 mock 服务器，默认关闭
+//!
+//! # qrcode
+//! 扫码登录时可以在终端中显示二维码，默认关闭
+//!
 
 #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
 compile_error!("至少应该启用一个 rustls 或是 native-tls features");
@@ -24,7 +33,15 @@ extern crate log;
 extern crate serde;
 
 pub mod connection;
+pub mod login;
+#[cfg(feature = "live")]
+pub mod record;
 pub mod requests;
+pub mod session;
+#[cfg(feature = "live")]
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(feature = "live")]
 pub mod ws_protocol;
 
@@ -36,9 +53,15 @@ pub enum Error {
     Network(#[from] reqwest::Error),
 
     #[cfg(feature = "live")]
-    /// 在连接 websocket 的时候可能发生的错误
+    /// 在连接 websocket 的时候可能发生的错误。
+    ///
+    /// tungstenite 的错误有一百多字节，放在 Box 中避免所有的 `Result` 都跟着变大
     #[error("Websocket error: {0}")]
-    WebSocket(#[from] async_tungstenite::tungstenite::Error),
+    WebSocket(Box<async_tungstenite::tungstenite::Error>),
+
+    /// 读写文件、TCP 连接时发生的 IO 错误
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 
     /// 在连接 http 的时候可能返回非 200 的返回码（如被频控、url 不存在）
     #[error("Unexpected status code: {0}")]
@@ -48,19 +71,231 @@ pub enum Error {
     #[error("Failed to parse http body as expected json.")]
     Serde(#[from] serde_json::Error),
 
-    /// 哔哩哔哩定义在 http 应用层之上的一个应用层错误
+    /// 哔哩哔哩定义在 http 应用层之上的一个应用层错误，url 为出错的请求地址
     #[error("Bilibili error: ({}) {}", .code, .message)]
-    BiliCustom { code: i64, message: String },
+    BiliCustom {
+        code: BiliErrorCode,
+        message: String,
+        url: Option<String>,
+    },
 
     /// 哔哩哔哩返回的结构中没有 data 字段
     #[error("The request seems ok but no data is found.")]
     DataNotFound,
 
+    #[cfg(feature = "live")]
+    /// 直播间 auth 被拒绝，code 为 AuthReply 中返回的 code；服务器没有回复直接断开连接时为 None
+    #[error("Live room authentication failed: code = {code:?}")]
+    LiveAuthFailed { code: Option<i64> },
+
+    #[cfg(feature = "live")]
+    /// 在规定时间内没有收到直播间的 AuthReply
+    #[error("Live room authentication timed out after {0:?}")]
+    LiveAuthTimeout(std::time::Duration),
+
+    #[cfg(feature = "live")]
+    /// 切换直播间失败，code 为 None 表示在规定时间内没有收到回复，或者连接在收到回复之前断开
+    #[error("Failed to change to live room {room_id}: code = {code:?}")]
+    LiveChangeRoomFailed { room_id: u64, code: Option<i64> },
+
+    #[cfg(feature = "live")]
+    /// 直播间连接已经被主动关闭
+    #[error("Live connection already closed")]
+    LiveClosed,
+
+    #[cfg(feature = "live")]
+    /// 直播间连接在一段时间内没有收到任何数据，可能已经断开
+    #[error("No packet received from live server in {0:?}")]
+    LiveTimeout(std::time::Duration),
+
     #[cfg(feature = "live")]
     /// 解析 websocket 协议时发生的错误
     #[error("Failed to parse as bilibili protocol: {0}")]
     Protocol(#[from] ws_protocol::ParseError),
+
+    #[cfg(feature = "qrcode")]
+    /// 生成二维码失败，一般是内容太长
+    #[error("Failed to generate qr code: {0}")]
+    QrCode(#[from] qrcode::types::QrError),
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+#[cfg(feature = "live")]
+impl From<async_tungstenite::tungstenite::Error> for Error {
+    fn from(e: async_tungstenite::tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(e))
+    }
+}
+
+/// 哔哩哔哩返回的错误码，见 [`Error::BiliCustom`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BiliErrorCode {
+    /// -101 账号未登录
+    NotLoggedIn,
+    /// -111 csrf 校验失败
+    CsrfFailed,
+    /// -352 风控校验失败
+    RiskControl,
+    /// -400 请求错误
+    BadRequest,
+    /// -403 访问权限不足
+    Forbidden,
+    /// -404 啥都木有
+    NotFound,
+    /// -412 请求被拦截
+    RequestBlocked,
+    /// -799 请求过于频繁
+    TooFrequent,
+    /// 19002000 直播间不存在
+    RoomNotFound,
+    /// 其他错误码
+    Other(i64),
+}
+
+impl BiliErrorCode {
+    /// 原始的数字错误码
+    pub fn code(&self) -> i64 {
+        match self {
+            Self::NotLoggedIn => -101,
+            Self::CsrfFailed => -111,
+            Self::RiskControl => -352,
+            Self::BadRequest => -400,
+            Self::Forbidden => -403,
+            Self::NotFound => -404,
+            Self::RequestBlocked => -412,
+            Self::TooFrequent => -799,
+            Self::RoomNotFound => 19002000,
+            Self::Other(code) => *code,
+        }
+    }
+
+    pub fn is_not_logged_in(&self) -> bool {
+        *self == Self::NotLoggedIn
+    }
+
+    /// 是否被频控，稍后重试可能成功
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, Self::RequestBlocked | Self::TooFrequent)
+    }
+}
+
+impl From<i64> for BiliErrorCode {
+    fn from(code: i64) -> Self {
+        match code {
+            -101 => Self::NotLoggedIn,
+            -111 => Self::CsrfFailed,
+            -352 => Self::RiskControl,
+            -400 => Self::BadRequest,
+            -403 => Self::Forbidden,
+            -404 => Self::NotFound,
+            -412 => Self::RequestBlocked,
+            -799 => Self::TooFrequent,
+            19002000 => Self::RoomNotFound,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl From<BiliErrorCode> for i64 {
+    fn from(code: BiliErrorCode) -> i64 {
+        code.code()
+    }
+}
+
+impl PartialEq<i64> for BiliErrorCode {
+    fn eq(&self, other: &i64) -> bool {
+        self.code() == *other
+    }
+}
+
+impl std::fmt::Display for BiliErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl Error {
+    /// b 站返回的错误码，不是 [`Error::BiliCustom`] 时为 None
+    pub fn bili_code(&self) -> Option<BiliErrorCode> {
+        match self {
+            Self::BiliCustom { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// 账号未登录或者登录已经失效
+    pub fn is_not_logged_in(&self) -> bool {
+        self.bili_code().is_some_and(|code| code.is_not_logged_in())
+    }
+
+    /// 是否被频控，包括 -412、-799 错误码和 HTTP 412/429
+    pub fn is_rate_limited(&self) -> bool {
+        use reqwest::StatusCode;
+        match self {
+            Self::StatusCode(status) => matches!(
+                *status,
+                StatusCode::PRECONDITION_FAILED | StatusCode::TOO_MANY_REQUESTS
+            ),
+            Self::BiliCustom { code, .. } => code.is_rate_limited(),
+            _ => false,
+        }
+    }
+
+    /// 是否是重试之后可能成功的错误，如被频控、服务器暂时不可用、网络超时等
+    pub fn is_retryable(&self) -> bool {
+        use reqwest::StatusCode;
+        match self {
+            Self::Network(e) => e.is_timeout() || e.is_connect(),
+            Self::StatusCode(status) => {
+                self.is_rate_limited()
+                    || matches!(
+                        *status,
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+            }
+            _ => self.is_rate_limited(),
+        }
+    }
+}
+
 pub use requests::Request;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bili_error_code() {
+        for code in [-101, -111, -352, -400, -403, -404, -412, -799, 19002000, -1] {
+            assert_eq!(BiliErrorCode::from(code).code(), code);
+        }
+        assert_eq!(BiliErrorCode::from(-101), BiliErrorCode::NotLoggedIn);
+        assert_eq!(BiliErrorCode::from(-1), BiliErrorCode::Other(-1));
+
+        let e = Error::BiliCustom {
+            code: BiliErrorCode::from(-799),
+            message: "请求过于频繁，请稍后再试".to_string(),
+            url: Some("https://api.bilibili.com/x/space/upstat?mid=1".to_string()),
+        };
+        assert!(e.is_rate_limited());
+        assert!(e.is_retryable());
+        assert!(!e.is_not_logged_in());
+        assert_eq!(e.bili_code(), Some(BiliErrorCode::TooFrequent));
+        assert_eq!(
+            e.to_string(),
+            "Bilibili error: (-799) 请求过于频繁，请稍后再试"
+        );
+
+        let e = Error::BiliCustom {
+            code: BiliErrorCode::NotLoggedIn,
+            message: "账号未登录".to_string(),
+            url: None,
+        };
+        assert!(e.is_not_logged_in());
+        assert!(!e.is_retryable());
+        assert!(Error::StatusCode(reqwest::StatusCode::TOO_MANY_REQUESTS).is_rate_limited());
+        assert!(!Error::DataNotFound.is_rate_limited());
+    }
+}
@@ -0,0 +1,378 @@
+//! 扫码登录
+//!
+//! [`QrLogin`] 申请一个二维码，然后按照固定的间隔轮询扫码结果，状态变化时通过 stream 返回 [`QrLoginState`]。
+//! 二维码失效（服务器返回失效或者超过 [`timeout`][`QrLogin::timeout`]）、登录成功或者出错后 stream 结束。
+//! [`Error::is_retryable`] 的错误（如网络超时、被频控）不会结束轮询，会在下一次继续尝试。
+//!
+//! 登录成功后 cookie 已经保存在 client 中，配合 [`Session`][`crate::session::Session`] 可以保存到文件。
+//!
+//! # Example
+//! ```no_run
+//! use biliapi::login::{QrLogin, QrLoginState};
+//! use futures::StreamExt;
+//!
+//! # async fn dox() -> biliapi::Result<()> {
+//! let client = biliapi::connection::Client::new()?;
+//! let login = QrLogin::start(&client).await?;
+//! println!("请扫码登录：{}", login.url());
+//!
+//! let mut states = login.states();
+//! while let Some(state) = states.next().await {
+//!     match state? {
+//!         QrLoginState::WaitingForScan => println!("等待扫码"),
+//!         QrLoginState::ScannedWaitingConfirm => println!("已扫码，请在手机上确认"),
+//!         QrLoginState::Expired => println!("二维码已失效"),
+//!         QrLoginState::Success { cookies, .. } => println!("登录成功：{}", cookies.dede_user_id),
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::time::{sleep_until, Instant};
+
+use crate::{
+    connection::Client,
+    requests::{QrLoginGenerate, QrLoginPoll},
+    BiliErrorCode, Error, Request, Result,
+};
+
+/// 扫码登录的状态
+#[derive(Debug, Clone, PartialEq)]
+pub enum QrLoginState {
+    /// 还没有扫码
+    WaitingForScan,
+    /// 已经扫码，等待在手机上确认
+    ScannedWaitingConfirm,
+    /// 二维码已经失效，需要重新 [`start`][`QrLogin::start`]
+    Expired,
+    /// 登录成功
+    Success {
+        cookies: LoginCookies,
+        /// 用于刷新 cookie
+        refresh_token: String,
+    },
+}
+
+impl QrLoginState {
+    /// 是否是最终状态，之后不会再有新的状态
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Expired | Self::Success { .. })
+    }
+
+    fn from_poll(poll: QrLoginPoll) -> Result<Self> {
+        match poll.code {
+            0 => Ok(Self::Success {
+                cookies: LoginCookies::from_url(&poll.url)?,
+                refresh_token: poll.refresh_token,
+            }),
+            86038 => Ok(Self::Expired),
+            86090 => Ok(Self::ScannedWaitingConfirm),
+            86101 => Ok(Self::WaitingForScan),
+            code => Err(Error::BiliCustom {
+                code: BiliErrorCode::from(code),
+                message: poll.message,
+                url: None,
+            }),
+        }
+    }
+}
+
+/// 登录成功后得到的 cookie，值与 cookie 中的一致（未经 url 解码）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginCookies {
+    pub sessdata: String,
+    /// csrf token
+    pub bili_jct: String,
+    /// 登录账号的 uid
+    pub dede_user_id: u64,
+    /// cookie 的过期时间
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl LoginCookies {
+    /// 从登录成功时返回的跨域 url 中解析
+    fn from_url(url: &str) -> Result<Self> {
+        let query = url.split_once('?').map(|(_, q)| q).unwrap_or_default();
+        let get = |name: &str| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        };
+        Ok(Self {
+            sessdata: get("SESSDATA").ok_or(Error::DataNotFound)?,
+            bili_jct: get("bili_jct").ok_or(Error::DataNotFound)?,
+            dede_user_id: get("DedeUserID")
+                .and_then(|id| id.parse().ok())
+                .ok_or(Error::DataNotFound)?,
+            expires: get("Expires")
+                .and_then(|t| t.parse().ok())
+                .and_then(|t| Utc.timestamp_opt(t, 0).single()),
+        })
+    }
+}
+
+/// 一次扫码登录，见 [模块文档][`self`]
+#[derive(Debug, Clone)]
+pub struct QrLogin {
+    client: Client,
+    url: String,
+    qrcode_key: String,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl QrLogin {
+    /// 申请一个二维码，登录成功后 cookie 会保存在 `client` 中
+    pub async fn start(client: &Client) -> Result<Self> {
+        let generated = QrLoginGenerate::request(client, ()).await?;
+        debug!("qr login started, key = {}", generated.qrcode_key);
+        Ok(Self {
+            client: client.clone(),
+            url: generated.url,
+            qrcode_key: generated.qrcode_key,
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(180),
+        })
+    }
+
+    /// 二维码的内容
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn qrcode_key(&self) -> &str {
+        &self.qrcode_key
+    }
+
+    /// 轮询的间隔，默认 2 秒
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// 从开始轮询起超过这个时间视为二维码失效，默认 180 秒，与服务器一致
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 开始轮询，只在状态变化时返回。
+    ///
+    /// 可以重试的错误只打印日志，在失效之前继续轮询；其他错误返回之后 stream 结束
+    pub fn states(self) -> BoxStream<'static, Result<QrLoginState>> {
+        struct Poller {
+            login: QrLogin,
+            deadline: Instant,
+            next_poll: Instant,
+            last: Option<QrLoginState>,
+            finished: bool,
+        }
+        let now = Instant::now();
+        let poller = Poller {
+            deadline: now + self.timeout,
+            next_poll: now,
+            login: self,
+            last: None,
+            finished: false,
+        };
+        stream::unfold(poller, |mut p| async move {
+            loop {
+                if p.finished {
+                    return None;
+                }
+                sleep_until(p.next_poll.min(p.deadline)).await;
+                if Instant::now() >= p.deadline {
+                    debug!("qr login timed out after {:?}", p.login.timeout);
+                    p.finished = true;
+                    return Some((Ok(QrLoginState::Expired), p));
+                }
+                p.next_poll = Instant::now() + p.login.interval;
+
+                let state = QrLoginPoll::request(&p.login.client, p.login.qrcode_key.clone())
+                    .await
+                    .and_then(QrLoginState::from_poll);
+                match state {
+                    Ok(state) if p.last.as_ref() == Some(&state) => continue,
+                    Ok(state) => {
+                        debug!("qr login state: {:?}", state);
+                        p.finished = state.is_finished();
+                        p.last = Some(state.clone());
+                        return Some((Ok(state), p));
+                    }
+                    Err(e) if e.is_retryable() => {
+                        warn!("qr login poll failed, retry later: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        p.finished = true;
+                        return Some((Err(e), p));
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// 渲染为可以在终端中显示的二维码，需要启用 `qrcode` feature。
+    ///
+    /// url 太长无法编码时返回 [`QrCode`][`crate::Error::QrCode`]
+    #[cfg(feature = "qrcode")]
+    pub fn render_terminal(&self) -> crate::Result<String> {
+        use qrcode::{render::unicode::Dense1x2, QrCode};
+        let code = QrCode::new(self.url.as_bytes())?;
+        // 终端一般是深色背景，反色显示
+        Ok(code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::Host,
+        testing::{Mock, MockResponse, MockServer},
+    };
+    use serde_json::json;
+
+    const POLL: &str = "/x/passport-login/web/qrcode/poll";
+
+    fn poll(code: i64, url: &str) -> MockResponse {
+        MockResponse::data(json!({
+            "url": url,
+            "refresh_token": if code == 0 { "f0e1d2c3b4a5" } else { "" },
+            "timestamp": 1697616000000i64,
+            "code": code,
+            "message": "",
+        }))
+    }
+
+    async fn start(server: &MockServer) -> QrLogin {
+        QrLogin::start(&server.client())
+            .await
+            .unwrap()
+            .interval(Duration::from_millis(10))
+    }
+
+    #[tokio::test]
+    async fn test_success() {
+        let server = MockServer::with_fixtures().await.unwrap();
+        let url = "https://passport.biligame.com/x/passport-login/web/crossDomain?DedeUserID=12345678&DedeUserID__ckMd5=abcdef&Expires=1713256447&SESSDATA=abc%2C1713256447%2Cdef&bili_jct=0123456789abcdef&gourl=https%3A%2F%2Fwww.bilibili.com";
+        let mut mock = Mock::get(Host::Passport, POLL);
+        for code in [86101, 86101, 86090, 0] {
+            mock = mock.respond_once(poll(code, url));
+        }
+        server.mount(mock);
+        let login = start(&server).await;
+        assert_eq!(login.qrcode_key(), "8f2c1a7e9b3d4c5f6a0e1b2d3c4f5a6b");
+
+        let states: Vec<_> = login.states().map(Result::unwrap).collect().await;
+        let cookies = LoginCookies {
+            sessdata: "abc%2C1713256447%2Cdef".into(),
+            bili_jct: "0123456789abcdef".into(),
+            dede_user_id: 12345678,
+            expires: Utc.timestamp_opt(1713256447, 0).single(),
+        };
+        assert_eq!(
+            states,
+            [
+                QrLoginState::WaitingForScan,
+                QrLoginState::ScannedWaitingConfirm,
+                QrLoginState::Success {
+                    cookies,
+                    refresh_token: "f0e1d2c3b4a5".into(),
+                },
+            ]
+        );
+        let polls = server.received().into_iter().filter(|r| r.path == POLL);
+        assert!(polls
+            .map(|r| r.query("qrcode_key").unwrap().to_string())
+            .all(|key| key == "8f2c1a7e9b3d4c5f6a0e1b2d3c4f5a6b"));
+    }
+
+    #[cfg(feature = "qrcode")]
+    #[tokio::test]
+    async fn test_render_terminal() {
+        let server = MockServer::with_fixtures().await.unwrap();
+        let mut login = start(&server).await;
+        assert!(login.render_terminal().unwrap().contains('█'));
+        // 超出二维码的容量时返回错误而不是 panic
+        login.url = "a".repeat(8000);
+        assert!(matches!(
+            login.render_terminal(),
+            Err(crate::Error::QrCode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired() {
+        let server = MockServer::with_fixtures().await.unwrap();
+        server.mount(Mock::get(Host::Passport, POLL).respond_once(poll(86038, "")));
+        let states: Vec<_> = start(&server).await.states().collect().await;
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].as_ref().unwrap(), &QrLoginState::Expired);
+
+        // 一直没有扫码，超时后视为失效
+        let login = start(&server).await.timeout(Duration::from_millis(50));
+        let states: Vec<_> = login.states().map(Result::unwrap).collect().await;
+        assert_eq!(
+            states,
+            [QrLoginState::WaitingForScan, QrLoginState::Expired]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retryable_error() {
+        let server = MockServer::with_fixtures().await.unwrap();
+        let url = "https://passport.biligame.com/x/passport-login/web/crossDomain?DedeUserID=1&SESSDATA=a&bili_jct=b";
+        server.mount(
+            Mock::get(Host::Passport, POLL)
+                .respond_once(poll(86101, ""))
+                .respond_once(MockResponse::status(
+                    reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                ))
+                .respond_once(MockResponse::bili_error(-412, "请求被拦截"))
+                .respond_once(poll(0, url)),
+        );
+        let states: Vec<_> = start(&server)
+            .await
+            .states()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0], QrLoginState::WaitingForScan);
+        assert!(matches!(states[1], QrLoginState::Success { .. }));
+
+        // 一直失败时到了失效时间仍然结束
+        let server = MockServer::with_fixtures().await.unwrap();
+        server.mount(
+            Mock::get(Host::Passport, POLL).respond(MockResponse::status(
+                reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            )),
+        );
+        let login = start(&server).await.timeout(Duration::from_millis(50));
+        let states: Vec<_> = login.states().map(Result::unwrap).collect().await;
+        assert_eq!(states, [QrLoginState::Expired]);
+    }
+
+    #[tokio::test]
+    async fn test_error() {
+        let server = MockServer::with_fixtures().await.unwrap();
+        server.mount(Mock::get(Host::Passport, POLL).respond(poll(86000, "")));
+        let states: Vec<_> = start(&server).await.states().collect().await;
+        assert_eq!(states.len(), 1);
+        assert_eq!(
+            states[0].as_ref().unwrap_err().bili_code(),
+            Some(86000.into())
+        );
+    }
+}
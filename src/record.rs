@@ -0,0 +1,406 @@
+//! 直播间录制，把收到的 [`Packet`] 写入 JSON Lines 文件
+//!
+//! 每个文件（分段）的第一行为 [`RecordHeader`]，之后每行为一个 [`Packet`]，可以使用 [`ReplayStream`] 重放
+//!
+//! # Example
+//! ```no_run
+//! use biliapi::record::{Compression, Recorder, RotatingFile};
+//! # tokio_test::block_on(async {
+//! let client = biliapi::connection::Client::new().unwrap();
+//! let sink = RotatingFile::new("records", 5440)
+//!     .max_size(64 * 1024 * 1024)
+//!     .compression(Compression::Gzip);
+//! let recorder = Recorder::new(client, 5440, sink);
+//! let handle = recorder.handle();
+//! tokio::spawn(recorder.run());
+//! println!("{:?}", handle.status());
+//! # });
+//! ```
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
+use tokio::sync::mpsc::{self, error::TryRecvError};
+
+use crate::{
+    connection::{
+        resolve_room_id, Client, ConnectionState, LiveConnectionBuilder, LiveMessage,
+        ReconnectPolicy, ReconnectingConnection,
+    },
+    ws_protocol::Packet,
+};
+
+mod export;
+pub use export::{AssOptions, DanmakuItem, DanmakuTrack};
+mod replay;
+pub use replay::{Pacing, ReplayStream};
+mod sink;
+pub use sink::{Compression, JsonLines, RecordSink, RotatingFile};
+
+/// 录制文件的格式名
+pub const FORMAT: &str = "biliapi-record";
+/// 录制文件的格式版本，格式不兼容时增加
+pub const FORMAT_VERSION: u32 = 1;
+
+/// 录制文件的第一行
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordHeader {
+    pub format: String,
+    pub version: u32,
+    pub room_id: u64,
+    pub created_at: DateTime<Local>,
+}
+
+impl RecordHeader {
+    pub fn new(room_id: u64) -> Self {
+        Self {
+            format: FORMAT.to_string(),
+            version: FORMAT_VERSION,
+            room_id,
+            created_at: Local::now(),
+        }
+    }
+
+    /// 是否为当前版本可以读取的格式
+    pub fn is_supported(&self) -> bool {
+        self.format == FORMAT && self.version <= FORMAT_VERSION
+    }
+}
+
+/// [`Recorder`] 的运行状态
+#[derive(Debug, Clone, Serialize)]
+pub struct RecorderStatus {
+    pub room_id: u64,
+    pub state: ConnectionState,
+    /// 写入的包的数量
+    pub packets: u64,
+    /// 写入的字节数（压缩前）
+    pub bytes: u64,
+    /// 分段数量
+    pub segments: u64,
+    /// 断线重连的次数
+    pub reconnects: u64,
+    pub started_at: DateTime<Local>,
+    /// 最后一次收到包的时间
+    pub last_packet_at: Option<DateTime<Local>>,
+}
+
+/// 查询 [`Recorder`] 状态的句柄，可以在录制开始之后使用
+#[derive(Debug, Clone)]
+pub struct RecorderHandle {
+    status: Arc<Mutex<RecorderStatus>>,
+}
+
+impl RecorderHandle {
+    /// 当前状态的快照
+    pub fn status(&self) -> RecorderStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// 直播间录制器，使用 [`ReconnectingConnection`] 连接直播间，并把所有的包写入 [`RecordSink`]
+///
+/// 写入在单独的阻塞线程中进行，不会阻塞 tokio 的 worker。
+/// `room_id` 可以是短号，开始录制时会先转换为长房号，状态和 sink 都使用长房号
+pub struct Recorder<S> {
+    client: Client,
+    room_id: u64,
+    sink: S,
+    policy: ReconnectPolicy,
+    builder: LiveConnectionBuilder,
+    status: Arc<Mutex<RecorderStatus>>,
+}
+
+impl<S: RecordSink + 'static> Recorder<S> {
+    /// 使用默认的重连策略和连接配置
+    pub fn new(client: Client, room_id: u64, sink: S) -> Self {
+        Self::with_config(
+            client,
+            room_id,
+            sink,
+            ReconnectPolicy::default(),
+            LiveConnectionBuilder::new(),
+        )
+    }
+
+    pub fn with_config(
+        client: Client,
+        room_id: u64,
+        sink: S,
+        policy: ReconnectPolicy,
+        builder: LiveConnectionBuilder,
+    ) -> Self {
+        let status = RecorderStatus {
+            room_id,
+            state: ConnectionState::Connecting,
+            packets: 0,
+            bytes: 0,
+            segments: 0,
+            reconnects: 0,
+            started_at: Local::now(),
+            last_packet_at: None,
+        };
+        Self {
+            client,
+            room_id,
+            sink,
+            policy,
+            builder,
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    pub fn handle(&self) -> RecorderHandle {
+        RecorderHandle {
+            status: self.status.clone(),
+        }
+    }
+
+    /// 一直录制，直到重连失败
+    pub async fn run(self) -> crate::Result<()> {
+        self.run_until(future::pending()).await
+    }
+
+    /// 录制直到 `shutdown` 完成或者重连失败，结束时会调用 [`RecordSink::finish`]
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> crate::Result<()> {
+        /// 写入线程来不及写入时最多缓存这么多包
+        const BUFFER: usize = 1024;
+
+        let room_id = match resolve_room_id(&self.client, self.room_id).await {
+            Ok(room_id) => room_id,
+            Err(e) => {
+                self.status.lock().unwrap().state = ConnectionState::Failed {
+                    reason: e.to_string(),
+                };
+                return Err(e);
+            }
+        };
+        self.status.lock().unwrap().room_id = room_id;
+        let mut connection = ReconnectingConnection::with_resolved_room(
+            self.client.clone(),
+            room_id,
+            self.policy.clone(),
+            self.builder.clone(),
+        );
+        let (tx, rx) = mpsc::channel(BUFFER);
+        let mut sink = self.sink;
+        sink.set_room_id(room_id);
+        let status = self.status.clone();
+        let writer = tokio::task::spawn_blocking(move || write_packets(sink, rx, status));
+
+        let mut shutdown = Box::pin(shutdown);
+        let result = loop {
+            let msg = match future::select(connection.next(), shutdown.as_mut()).await {
+                Either::Left((Some(msg), _)) => msg,
+                Either::Left((None, _)) => break Ok(()),
+                Either::Right(_) => {
+                    info!("recorder of room {} stopped", room_id);
+                    break Ok(());
+                }
+            };
+            match msg {
+                Ok(LiveMessage::Packet(packet)) => {
+                    // 写入线程出错退出了，错误在下面 join 时拿到
+                    if tx.send(packet).await.is_err() {
+                        break Ok(());
+                    }
+                }
+                Ok(msg) => update_state(&self.status, msg),
+                Err(e) => {
+                    self.status.lock().unwrap().state = ConnectionState::Failed {
+                        reason: e.to_string(),
+                    };
+                    break Err(e);
+                }
+            }
+        };
+        drop(tx);
+        let written = match writer.await {
+            Ok(written) => written,
+            Err(e) => Err(std::io::Error::other(e).into()),
+        };
+        result.and(written)
+    }
+}
+
+/// 在阻塞线程中把收到的包写入 `sink`，channel 关闭之后调用 [`RecordSink::finish`]。
+///
+/// channel 空了之后立即 flush，一直有包时最多间隔 `FLUSH_INTERVAL` flush 一次
+fn write_packets(
+    mut sink: impl RecordSink,
+    mut rx: mpsc::Receiver<Packet>,
+    status: Arc<Mutex<RecorderStatus>>,
+) -> crate::Result<()> {
+    /// 最多间隔这么久 flush 一次
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+    let mut last_flush = Instant::now();
+    let mut dirty = false;
+    let result = (|| -> crate::Result<()> {
+        loop {
+            let packet = match rx.try_recv() {
+                Ok(packet) => packet,
+                Err(TryRecvError::Empty) => {
+                    // 等待下一个包之前把已经写入的 flush 掉
+                    if dirty {
+                        last_flush = Instant::now();
+                        dirty = false;
+                        sink.flush()?;
+                    }
+                    match rx.blocking_recv() {
+                        Some(packet) => packet,
+                        None => break,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+            let bytes = sink.write_packet(&packet)?;
+            {
+                let mut status = status.lock().unwrap();
+                status.packets += 1;
+                status.bytes += bytes as u64;
+                status.segments = sink.segments();
+                status.last_packet_at = Some(packet.time);
+            }
+            dirty = true;
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                last_flush = Instant::now();
+                dirty = false;
+                sink.flush()?;
+            }
+        }
+        Ok(())
+    })();
+    // 出错时让 recorder 停止发送
+    rx.close();
+    let finished = sink.finish();
+    result.and(finished)
+}
+
+fn update_state(status: &Mutex<RecorderStatus>, msg: LiveMessage) {
+    let mut status = status.lock().unwrap();
+    status.state = match msg {
+        LiveMessage::Connected { url } => ConnectionState::Connected { url },
+        LiveMessage::Reconnected { url } => {
+            status.reconnects += 1;
+            ConnectionState::Connected { url }
+        }
+        LiveMessage::Reconnecting {
+            attempt, reason, ..
+        } => ConnectionState::Reconnecting { attempt, reason },
+        LiveMessage::Packet(_) => return,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{Host, Transport},
+        testing::{LiveScript, Mock, MockLiveServer, MockServer},
+        ws_protocol::{KnownOperation, Operation},
+    };
+    use std::io::BufRead;
+
+    #[tokio::test]
+    async fn test_recorder() {
+        let live = MockLiveServer::start().await.unwrap();
+        let danmaku = |body| Packet::new(Operation::Known(KnownOperation::SendMsgReply), body, 0);
+        live.push_script(LiveScript::new().send(&danmaku("a")).send(&danmaku("b")));
+        let http = MockServer::with_fixtures().await.unwrap();
+        http.mount(
+            Mock::get(Host::ApiLive, "/xlive/web-room/v1/index/getDanmuInfo")
+                .respond(live.danmu_info("token")),
+        );
+
+        let dir = std::env::temp_dir().join(format!("biliapi-recorder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let recorder = Recorder::with_config(
+            http.client(),
+            646,
+            RotatingFile::new(&dir, 646),
+            ReconnectPolicy::default(),
+            LiveConnectionBuilder::new().transport(Transport::Tcp),
+        );
+        let handle = recorder.handle();
+        let status = handle.clone();
+        let shutdown = async move {
+            while status.status().packets < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        recorder.run_until(shutdown).await.unwrap();
+        assert_eq!(handle.status().segments, 1);
+        // 短号 646 转换为长房号
+        assert_eq!(handle.status().room_id, 21133);
+
+        let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        assert!(file.file_name().to_str().unwrap().starts_with("21133-"));
+        let content = std::fs::read(file.path()).unwrap();
+        let mut lines = content.lines();
+        let header: RecordHeader = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(header.room_id, 21133);
+        let bodies: Vec<String> = lines
+            .map(|line| serde_json::from_str::<Packet>(&line.unwrap()).unwrap())
+            .filter(|p| p.operation == Operation::Known(KnownOperation::SendMsgReply))
+            .map(|p| p.body)
+            .collect();
+        assert_eq!(bodies, ["a", "b"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 记录 flush 时已经写入了多少个包
+    struct CountingSink(Arc<Mutex<(usize, Vec<usize>)>>);
+
+    impl RecordSink for CountingSink {
+        fn write_packet(&mut self, _packet: &Packet) -> crate::Result<usize> {
+            self.0.lock().unwrap().0 += 1;
+            Ok(1)
+        }
+        fn flush(&mut self) -> crate::Result<()> {
+            let mut counts = self.0.lock().unwrap();
+            let written = counts.0;
+            counts.1.push(written);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_when_idle() {
+        let counts = Arc::new(Mutex::new((0, vec![])));
+        let (tx, rx) = mpsc::channel(16);
+        let sink = CountingSink(counts.clone());
+        let status = Arc::new(Mutex::new(RecorderStatus {
+            room_id: 0,
+            state: ConnectionState::Connecting,
+            packets: 0,
+            bytes: 0,
+            segments: 0,
+            reconnects: 0,
+            started_at: Local::now(),
+            last_packet_at: None,
+        }));
+        let writer = tokio::task::spawn_blocking(move || write_packets(sink, rx, status));
+        let packet = || Packet::new(Operation::Known(KnownOperation::SendMsgReply), "a", 0);
+        tx.send(packet()).await.unwrap();
+        tx.send(packet()).await.unwrap();
+        // 没有新的包也会在 FLUSH_INTERVAL 之前 flush
+        tokio::time::timeout(Duration::from_millis(500), async {
+            while counts.lock().unwrap().1.last() != Some(&2) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        drop(tx);
+        writer.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,524 @@
+//! 把录制的弹幕导出为 B 站弹幕 XML 和 ASS 字幕，用来叠加到录像上
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Utc};
+
+use crate::ws_protocol::{event::Danmaku, LiveEvent, Packet, ParseError};
+
+/// 导出时使用的一条弹幕，时间为相对于开播的偏移
+#[derive(Debug, Clone, PartialEq)]
+pub struct DanmakuItem {
+    pub offset: Duration,
+    pub text: String,
+    /// 1 滚动，4 底部，5 顶部
+    pub mode: u32,
+    pub font_size: u32,
+    pub color: u32,
+    pub uid: u64,
+    /// 发送者 uid 的 crc32，即 XML 中的用户 hash
+    pub user_hash: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// 按时间顺序收集的弹幕
+///
+/// 时间起点默认为第一个 LIVE 事件，没有收到 LIVE 时为第一个包的时间，
+/// 也可以用 [`with_start`][`DanmakuTrack::with_start`] 指定。早于起点的弹幕不会导出。
+///
+/// # Example
+/// ```no_run
+/// use biliapi::record::{AssOptions, DanmakuTrack, ReplayStream, Pacing};
+/// use futures::StreamExt;
+/// # tokio_test::block_on(async {
+/// let mut replay = ReplayStream::open("records/5440.jsonl").unwrap().pacing(Pacing::Unlimited);
+/// let mut track = DanmakuTrack::new();
+/// while let Some(packet) = replay.next().await {
+///     track.push(&packet.unwrap()).unwrap();
+/// }
+/// track.write_xml(std::fs::File::create("5440.xml").unwrap()).unwrap();
+/// track.write_ass(&AssOptions::default(), std::fs::File::create("5440.ass").unwrap()).unwrap();
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DanmakuTrack {
+    /// 指定的起点，设置之后忽略 LIVE 事件
+    start: Option<DateTime<Local>>,
+    live_at: Option<DateTime<Local>>,
+    first_packet_at: Option<DateTime<Local>>,
+    danmaku: Vec<(DateTime<Local>, Danmaku)>,
+}
+
+impl DanmakuTrack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用指定的时间作为起点，如录像开始的时间
+    pub fn with_start(start: DateTime<Local>) -> Self {
+        Self {
+            start: Some(start),
+            ..Self::default()
+        }
+    }
+
+    /// 收集一个 packet 中的弹幕和 LIVE 事件，其他的包只读取 cmd 后忽略
+    ///
+    /// 格式有变化、无法解析的弹幕会打印日志后跳过
+    pub fn push(&mut self, packet: &Packet) -> Result<(), ParseError> {
+        self.first_packet_at.get_or_insert(packet.time);
+        let cmd = match packet.event_cmd() {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+        if !matches!(cmd.split(':').next(), Some("DANMU_MSG" | "LIVE")) {
+            return Ok(());
+        }
+        match packet.decode_event()? {
+            Some(LiveEvent::Danmaku(danmaku)) => self.push_danmaku(packet.time, danmaku),
+            Some(LiveEvent::Live { .. }) => {
+                self.live_at.get_or_insert(packet.time);
+            }
+            _ => debug!("failed to decode {}, skipped", cmd),
+        }
+        Ok(())
+    }
+
+    /// 收集一条已经解析的弹幕，time 为收到弹幕的时间
+    pub fn push_danmaku(&mut self, time: DateTime<Local>, danmaku: Danmaku) {
+        self.first_packet_at.get_or_insert(time);
+        self.danmaku.push((time, danmaku));
+    }
+
+    /// 时间起点
+    pub fn start(&self) -> Option<DateTime<Local>> {
+        self.start.or(self.live_at).or(self.first_packet_at)
+    }
+
+    /// 按照时间排序的弹幕
+    pub fn items(&self) -> Vec<DanmakuItem> {
+        let start = match self.start() {
+            Some(start) => start,
+            None => return vec![],
+        };
+        let mut items: Vec<_> = self
+            .danmaku
+            .iter()
+            .filter_map(|(time, d)| {
+                Some(DanmakuItem {
+                    offset: (*time - start).to_std().ok()?,
+                    text: d.text.clone(),
+                    mode: d.mode,
+                    font_size: d.font_size,
+                    color: d.color,
+                    uid: d.sender.uid,
+                    user_hash: d.sender.hash.clone(),
+                    timestamp: d.timestamp,
+                })
+            })
+            .collect();
+        items.sort_by_key(|item| item.offset);
+        items
+    }
+
+    /// 导出为 B 站的弹幕 XML 格式
+    pub fn write_xml(&self, mut w: impl Write) -> io::Result<()> {
+        let items = self.items();
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            w,
+            "<i><chatserver>chat.bilibili.com</chatserver><chatid>0</chatid><mission>0</mission>\
+             <maxlimit>{}</maxlimit><state>0</state><real_name>0</real_name><source>k-v</source>",
+            items.len()
+        )?;
+        for (i, item) in items.iter().enumerate() {
+            // 出现时间,模式,字号,颜色,发送时间戳,弹幕池,用户 hash,弹幕 id
+            writeln!(
+                w,
+                r#"<d p="{:.3},{},{},{},{},0,{},{}">{}</d>"#,
+                item.offset.as_secs_f64(),
+                item.mode,
+                item.font_size,
+                item.color,
+                item.timestamp.timestamp(),
+                escape_xml(&item.user_hash),
+                i,
+                escape_xml(&item.text)
+            )?;
+        }
+        writeln!(w, "</i>")?;
+        w.flush()
+    }
+
+    /// 导出为 ASS 字幕，返回写入的弹幕数量，没有空位的弹幕会被丢弃
+    pub fn write_ass(&self, options: &AssOptions, mut w: impl Write) -> io::Result<usize> {
+        let items = self.items();
+        let mut layout = Layout::new(options);
+        let mut events = String::new();
+        let mut written = 0;
+        for item in &items {
+            if let Some(effect) = layout.place(item) {
+                written += 1;
+                let start = item.offset;
+                let end = start + layout.duration(item);
+                let _ = writeln!(
+                    events,
+                    "Dialogue: 0,{},{},Danmaku,,0,0,0,,{{{}\\c&H{}&}}{}",
+                    ass_time(start),
+                    ass_time(end),
+                    effect,
+                    ass_color(item.color),
+                    escape_ass(&item.text)
+                );
+            }
+        }
+        debug!("{} of {} danmaku written to ass", written, items.len());
+
+        let alpha = ((1.0 - options.opacity.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        write!(
+            w,
+            "[Script Info]\n\
+             ScriptType: v4.00+\n\
+             PlayResX: {width}\n\
+             PlayResY: {height}\n\
+             WrapStyle: 2\n\
+             ScaledBorderAndShadow: yes\n\
+             \n\
+             [V4+ Styles]\n\
+             Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, \
+             BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, \
+             BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+             Style: Danmaku,{font},{size},&H{alpha:02X}FFFFFF,&H{alpha:02X}FFFFFF,&H{alpha:02X}000000,\
+             &H{alpha:02X}000000,0,0,0,0,100,100,0,0,1,1,0,7,0,0,0,1\n\
+             \n\
+             [Events]\n\
+             Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+            width = options.width,
+            height = options.height,
+            font = options.font_name,
+            size = options.font_size,
+            alpha = alpha,
+        )?;
+        w.write_all(events.as_bytes())?;
+        w.flush()?;
+        Ok(written)
+    }
+}
+
+/// ASS 字幕的样式
+#[derive(Debug, Clone)]
+pub struct AssOptions {
+    /// 画面宽度，默认 1920
+    pub width: u32,
+    /// 画面高度，默认 1080
+    pub height: u32,
+    /// 默认 Microsoft YaHei
+    pub font_name: String,
+    /// 标准字号（25）的弹幕对应的像素大小，默认 48
+    pub font_size: u32,
+    /// 滚动弹幕从右边进入到完全离开的时间，默认 8 秒
+    pub scroll_duration: Duration,
+    /// 顶部和底部弹幕停留的时间，默认 5 秒
+    pub fixed_duration: Duration,
+    /// 弹幕可以使用的画面高度比例，默认 1.0
+    pub area: f64,
+    /// 不透明度，默认 0.8
+    pub opacity: f64,
+}
+
+impl Default for AssOptions {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            font_name: "Microsoft YaHei".to_string(),
+            font_size: 48,
+            scroll_duration: Duration::from_secs(8),
+            fixed_duration: Duration::from_secs(5),
+            area: 1.0,
+            opacity: 0.8,
+        }
+    }
+}
+
+/// 弹幕的轨道分配
+struct Layout<'a> {
+    options: &'a AssOptions,
+    /// 每条滚动轨道上最后一条弹幕的开始时间和宽度
+    scroll: Vec<Option<(f64, f64)>>,
+    /// 每条顶部 / 底部轨道空出来的时间
+    top: Vec<f64>,
+    bottom: Vec<f64>,
+}
+
+impl<'a> Layout<'a> {
+    fn new(options: &'a AssOptions) -> Self {
+        let lanes = (options.height as f64 * options.area.clamp(0.0, 1.0)
+            / options.font_size.max(1) as f64)
+            .floor()
+            .max(1.0) as usize;
+        Self {
+            options,
+            scroll: vec![None; lanes],
+            top: vec![0.0; lanes],
+            bottom: vec![0.0; lanes],
+        }
+    }
+
+    fn duration(&self, item: &DanmakuItem) -> Duration {
+        match item.mode {
+            4 | 5 => self.options.fixed_duration,
+            _ => self.options.scroll_duration,
+        }
+    }
+
+    fn font_size(&self, item: &DanmakuItem) -> f64 {
+        self.options.font_size as f64 * item.font_size.max(1) as f64 / 25.0
+    }
+
+    /// 估算的文字宽度，ASCII 字符按半角计算
+    fn text_width(&self, item: &DanmakuItem) -> f64 {
+        let chars: f64 = item
+            .text
+            .chars()
+            .map(|c| if c.is_ascii() { 0.5 } else { 1.0 })
+            .sum();
+        chars * self.font_size(item)
+    }
+
+    /// 找到不会和已有弹幕重叠的轨道，返回对应的 ASS 定位标签。
+    ///
+    /// 字号比默认大的弹幕会占用连续的多条轨道
+    fn place(&mut self, item: &DanmakuItem) -> Option<String> {
+        let t = item.offset.as_secs_f64();
+        let lane_height = self.options.font_size.max(1) as f64;
+        let (width, height) = (self.options.width as f64, self.options.height as f64);
+        let span = (self.font_size(item) / lane_height).ceil().max(1.0) as usize;
+        match item.mode {
+            5 | 4 => {
+                let lanes = if item.mode == 5 {
+                    &mut self.top
+                } else {
+                    &mut self.bottom
+                };
+                let lane = find_lanes(lanes, span, |free_at| *free_at <= t)?;
+                let span = span.min(lanes.len());
+                lanes[lane..lane + span].fill(t + self.options.fixed_duration.as_secs_f64());
+                Some(if item.mode == 5 {
+                    format!("\\an8\\pos({},{})", width / 2.0, lane as f64 * lane_height)
+                } else {
+                    format!(
+                        "\\an2\\pos({},{})",
+                        width / 2.0,
+                        height - lane as f64 * lane_height
+                    )
+                })
+            }
+            _ => {
+                let duration = self.options.scroll_duration.as_secs_f64();
+                let w = self.text_width(item);
+                let speed = (width + w) / duration;
+                let lane = find_lanes(&self.scroll, span, |last| match *last {
+                    None => true,
+                    Some((start, last_w)) => {
+                        let last_speed = (width + last_w) / duration;
+                        // 上一条已经完全进入画面，并且这一条到达左边时上一条已经离开
+                        start + last_w / last_speed <= t && t + width / speed >= start + duration
+                    }
+                })?;
+                let span = span.min(self.scroll.len());
+                self.scroll[lane..lane + span].fill(Some((t, w)));
+                let y = lane as f64 * lane_height;
+                Some(format!("\\move({},{},{},{})", width, y, -w, y))
+            }
+        }
+    }
+}
+
+/// 找到连续 `span` 条都空闲的轨道，返回第一条。比全部轨道还高时只要求全部空闲
+fn find_lanes<T>(lanes: &[T], span: usize, free: impl Fn(&T) -> bool) -> Option<usize> {
+    let span = span.min(lanes.len());
+    (0..=lanes.len() - span).find(|&lane| lanes[lane..lane + span].iter().all(&free))
+}
+
+fn ass_time(d: Duration) -> String {
+    let cs = d.as_millis() / 10;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+/// ASS 的颜色顺序为 BGR
+fn ass_color(rgb: u32) -> String {
+    let (r, g, b) = ((rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF);
+    format!("{:02X}{:02X}{:02X}", b, g, r)
+}
+
+fn escape_ass(text: &str) -> String {
+    text.replace('\\', "＼")
+        .replace('{', "｛")
+        .replace('}', "｝")
+        .replace('\n', "\\N")
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut s = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '>' => s.push_str("&gt;"),
+            '"' => s.push_str("&quot;"),
+            '\'' => s.push_str("&apos;"),
+            // XML 1.0 中不允许的控制字符
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws_protocol::{KnownOperation, Operation};
+
+    fn packet(start: DateTime<Local>, secs: i64, body: String) -> Packet {
+        Packet {
+            time: start + chrono::Duration::seconds(secs),
+            ..Packet::new(Operation::Known(KnownOperation::SendMsgReply), body, 1)
+        }
+    }
+
+    fn danmaku(start: DateTime<Local>, secs: i64, text: &str, mode: u32) -> Packet {
+        sized_danmaku(start, secs, text, mode, 25)
+    }
+
+    fn sized_danmaku(
+        start: DateTime<Local>,
+        secs: i64,
+        text: &str,
+        mode: u32,
+        font_size: u32,
+    ) -> Packet {
+        let body = format!(
+            r#"{{"cmd":"DANMU_MSG","info":[[0,{},{},16711680,1650000000123,0,0,"5f3e2a1b",0,0,0,"",0,"{{}}","{{}}",{{}}],"{}",[42,"u",0,0,0,10000,1,""],[],[0,0,0,"",0],["",""],0,0,null,{{}},0,0,null,null,0,105]}}"#,
+            mode, font_size, text
+        );
+        packet(start, secs, body)
+    }
+
+    fn track(packets: &[Packet]) -> DanmakuTrack {
+        let mut track = DanmakuTrack::new();
+        for p in packets {
+            track.push(p).unwrap();
+        }
+        track
+    }
+
+    #[test]
+    fn test_offset_from_live() {
+        let start = Local::now();
+        let track = track(&[
+            danmaku(start, 0, "before", 1),
+            packet(start, 10, r#"{"cmd":"LIVE","roomid":1}"#.to_string()),
+            danmaku(start, 15, "after", 1),
+        ]);
+        let items = track.items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].text, "after");
+        assert_eq!(items[0].offset, Duration::from_secs(5));
+
+        let mut xml = vec![];
+        track.write_xml(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains(r#"<d p="5.000,1,25,16711680,1650000000,0,5f3e2a1b,0">after</d>"#));
+    }
+
+    #[test]
+    fn test_skip_other_cmds() {
+        let start = Local::now();
+        let track = track(&[
+            packet(
+                start,
+                0,
+                r#"{"cmd":"SEND_GIFT","data":{"bad":1}}"#.to_string(),
+            ),
+            packet(
+                start,
+                1,
+                r#"{"cmd":"DANMU_MSG","info":{"bad":1}}"#.to_string(),
+            ),
+            danmaku(start, 2, "ok", 1),
+        ]);
+        assert_eq!(track.items().len(), 1);
+        assert_eq!(track.items()[0].text, "ok");
+    }
+
+    #[test]
+    fn test_ass_lanes() {
+        let start = Local::now();
+        let track = track(&[
+            danmaku(start, 0, "a", 1),
+            danmaku(start, 0, "b", 1),
+            danmaku(start, 0, "top", 5),
+            danmaku(start, 0, "top", 5),
+            danmaku(start, 1, "dropped", 5),
+            danmaku(start, 1, "<{x}>", 4),
+        ]);
+        let options = AssOptions {
+            height: 96,
+            ..AssOptions::default()
+        };
+        let mut ass = vec![];
+        // 只有两条轨道，第三条顶部弹幕没有空位
+        assert_eq!(track.write_ass(&options, &mut ass).unwrap(), 5);
+        let ass = String::from_utf8(ass).unwrap();
+        assert!(ass.contains("PlayResY: 96"));
+        assert!(ass
+            .contains("0:00:00.00,0:00:08.00,Danmaku,,0,0,0,,{\\move(1920,0,-24,0)\\c&H0000FF&}a"));
+        assert!(ass.contains("{\\move(1920,48,-24,48)\\c&H0000FF&}b"));
+        assert!(ass.contains("{\\an8\\pos(960,0)\\c&H0000FF&}top"));
+        assert!(ass.contains("{\\an8\\pos(960,48)\\c&H0000FF&}top"));
+        assert!(!ass.contains("dropped"));
+        assert!(ass.contains("{\\an2\\pos(960,96)\\c&H0000FF&}<｛x｝>"));
+    }
+
+    #[test]
+    fn test_ass_large_lanes() {
+        let start = Local::now();
+        let track = track(&[
+            sized_danmaku(start, 0, "big", 1, 36),
+            danmaku(start, 0, "small", 1),
+            sized_danmaku(start, 0, "big", 5, 36),
+            danmaku(start, 0, "small", 5),
+            sized_danmaku(start, 1, "dropped", 5, 36),
+        ]);
+        let options = AssOptions {
+            height: 144,
+            ..AssOptions::default()
+        };
+        let mut ass = vec![];
+        // 大字号的弹幕占用两条轨道，之后的弹幕只能放在第三条
+        assert_eq!(track.write_ass(&options, &mut ass).unwrap(), 4);
+        let ass = String::from_utf8(ass).unwrap();
+        assert!(ass.contains("\\move(1920,0,"));
+        assert!(ass.contains("{\\move(1920,96,-120,96)\\c&H0000FF&}small"));
+        assert!(ass.contains("\\an8\\pos(960,0)"));
+        assert!(ass.contains("{\\an8\\pos(960,96)\\c&H0000FF&}small"));
+        assert!(!ass.contains("dropped"));
+    }
+
+    #[test]
+    fn test_ass_time() {
+        assert_eq!(ass_time(Duration::from_millis(3_723_456)), "1:02:03.45");
+    }
+}
@@ -0,0 +1,393 @@
+//! 重放录制的直播
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use chrono::{DateTime, Local};
+use futures::{Future, Stream};
+use tokio::time::{Instant, Sleep};
+use tokio_util::codec::Decoder;
+
+use super::RecordHeader;
+use crate::ws_protocol::{Packet, PacketDecoder, ParseError};
+
+/// 重放的速度
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Pacing {
+    /// 按照 [`Packet::time`] 的间隔重放，默认
+    #[default]
+    RealTime,
+    /// 按照倍速重放，如 `Scaled(10.0)` 为十倍速
+    Scaled(f64),
+    /// 不等待，尽快读出所有的包
+    Unlimited,
+}
+
+impl Pacing {
+    fn scale(&self) -> Option<f64> {
+        match self {
+            Self::RealTime => Some(1.0),
+            Self::Scaled(scale) if *scale > 0.0 => Some(*scale),
+            Self::Scaled(_) | Self::Unlimited => None,
+        }
+    }
+}
+
+enum Source<R> {
+    /// [`JsonLines`][`super::JsonLines`] 格式，可以是多个分段拼接起来的
+    JsonLines { reader: R, line: String },
+    /// 原始的二进制帧，如 [`Packet::raw`] 拼接起来的数据。
+    ///
+    /// 帧中没有时间，[`Packet::time`] 为解析的时间，所以不能按照速度重放，也不能跳转
+    RawFrames {
+        reader: R,
+        decoder: PacketDecoder,
+        buffer: BytesMut,
+        decoded: std::vec::IntoIter<Packet>,
+    },
+}
+
+/// 重放录制的文件，和 [`LiveConnection`][`crate::connection::LiveConnection`] 一样实现了
+/// `Stream<Item = crate::Result<Packet>>`，可以直接替换直播间连接用来调试
+///
+/// 读取文件是同步进行的，对于本地文件影响不大。
+///
+/// 只有 JSON Lines 记录了每个包的时间，原始二进制帧总是尽快读出，[`pacing`][`ReplayStream::pacing`]
+/// 和 [`seek`][`ReplayStream::seek`] 对它们不起作用
+///
+/// # Example
+/// ```no_run
+/// use biliapi::record::{Pacing, ReplayStream};
+/// use futures::StreamExt;
+/// # tokio_test::block_on(async {
+/// let mut replay = ReplayStream::open("records/5440.jsonl")
+///     .unwrap()
+///     .pacing(Pacing::Scaled(10.0));
+/// while let Some(packet) = replay.next().await {
+///     let packet = packet.unwrap();
+/// }
+/// # });
+/// ```
+pub struct ReplayStream<R> {
+    source: Source<R>,
+    pacing: Pacing,
+    header: Option<RecordHeader>,
+    seek_to: Option<DateTime<Local>>,
+    /// 第一个包的时间和重放开始的时间，用来计算后续的包什么时候发出
+    base: Option<(DateTime<Local>, Instant)>,
+    /// 等待发出的包
+    pending: Option<(Packet, Pin<Box<Sleep>>)>,
+    /// 跳转时保留下来的、已经读出但还没有发出的包
+    peeked: Option<Packet>,
+    finished: bool,
+}
+
+impl ReplayStream<Box<dyn BufRead + Send>> {
+    /// 打开录制的文件，`.gz`（以及启用 `zstd` feature 时的 `.zst`）会自动解压。
+    ///
+    /// 以 `{` 开头的文件按照 JSON Lines 读取，否则按照原始的二进制帧读取，这时没有房间号，为 0
+    pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mut reader: Box<dyn BufRead + Send> =
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("gz") => Box::new(BufReader::new(flate2::read::GzDecoder::new(file))),
+                #[cfg(feature = "zstd")]
+                Some("zst") => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+                _ => Box::new(BufReader::new(file)),
+            };
+        if reader.fill_buf()?.first() == Some(&b'{') {
+            Ok(Self::json_lines(reader))
+        } else {
+            Ok(Self::raw_frames(reader, 0))
+        }
+    }
+}
+
+impl<R: BufRead> ReplayStream<R> {
+    fn new(source: Source<R>) -> Self {
+        Self {
+            source,
+            pacing: Pacing::default(),
+            header: None,
+            seek_to: None,
+            base: None,
+            pending: None,
+            peeked: None,
+            finished: false,
+        }
+    }
+
+    /// 读取 [`Recorder`][`super::Recorder`] 写入的 JSON Lines
+    pub fn json_lines(reader: R) -> Self {
+        Self::new(Source::JsonLines {
+            reader,
+            line: String::new(),
+        })
+    }
+
+    /// 读取拼接起来的原始二进制帧，解析出的 packet 带上 room_id。总是尽快读出，不能跳转
+    pub fn raw_frames(reader: R, room_id: u64) -> Self {
+        Self::new(Source::RawFrames {
+            reader,
+            decoder: PacketDecoder::new(room_id),
+            buffer: BytesMut::new(),
+            decoded: vec![].into_iter(),
+        })
+    }
+
+    /// 重放的速度，默认为 [`Pacing::RealTime`]，对原始二进制帧不起作用
+    pub fn pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// 跳过时间早于 `time` 的包，从第一个不早于 `time` 的包开始按照设置的速度重放
+    ///
+    /// 只能向后跳转，已经读出的包不会再次读出。原始二进制帧没有录制时间，不能跳转
+    pub fn seek(&mut self, time: DateTime<Local>) {
+        if self.is_raw() {
+            warn!("raw frames have no recorded time, seek is ignored");
+            return;
+        }
+        self.seek_to = Some(time);
+        self.base = None;
+        // 正在等待的包不早于 time 时保留下来，作为跳转后的第一个包
+        if let Some((packet, _)) = self.pending.take() {
+            if packet.time >= time {
+                self.peeked = Some(packet);
+            }
+        }
+    }
+
+    fn is_raw(&self) -> bool {
+        matches!(self.source, Source::RawFrames { .. })
+    }
+
+    /// 最近读到的录制文件头，原始二进制帧没有文件头
+    pub fn header(&self) -> Option<&RecordHeader> {
+        self.header.as_ref()
+    }
+
+    /// 读出下一个包，不考虑时间
+    fn next_packet(&mut self) -> crate::Result<Option<Packet>> {
+        if let Some(packet) = self.peeked.take() {
+            return Ok(Some(packet));
+        }
+        match &mut self.source {
+            Source::JsonLines { reader, line } => loop {
+                line.clear();
+                if reader.read_line(line)? == 0 {
+                    return Ok(None);
+                }
+                let trimmed = line.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+                // 多个分段拼接起来时中间会有文件头
+                if trimmed.contains(r#""format""#) {
+                    if let Ok(header) = serde_json::from_str::<RecordHeader>(trimmed) {
+                        if !header.is_supported() {
+                            return Err(ParseError::Malformed(format!(
+                                "unsupported record format {} version {}",
+                                header.format, header.version
+                            ))
+                            .into());
+                        }
+                        self.header = Some(header);
+                        continue;
+                    }
+                }
+                return Ok(Some(serde_json::from_str(trimmed)?));
+            },
+            Source::RawFrames {
+                reader,
+                decoder,
+                buffer,
+                decoded,
+            } => loop {
+                if let Some(packet) = decoded.next() {
+                    return Ok(Some(packet));
+                }
+                if let Some(packets) = decoder.decode(buffer)? {
+                    *decoded = packets.into_iter();
+                    continue;
+                }
+                let chunk = reader.fill_buf()?;
+                if chunk.is_empty() {
+                    if !buffer.is_empty() {
+                        return Err(ParseError::Malformed(format!(
+                            "truncated frame, {} bytes left",
+                            buffer.len()
+                        ))
+                        .into());
+                    }
+                    return Ok(None);
+                }
+                let n = chunk.len();
+                buffer.extend_from_slice(chunk);
+                reader.consume(n);
+            },
+        }
+    }
+
+    /// 包应该发出的时间，不需要等待时返回 None
+    fn deadline(&mut self, packet: &Packet) -> Option<Instant> {
+        if self.is_raw() {
+            return None;
+        }
+        let scale = self.pacing.scale()?;
+        let (base_time, base_instant) = *self
+            .base
+            .get_or_insert_with(|| (packet.time, Instant::now()));
+        let offset = (packet.time - base_time).to_std().unwrap_or_default();
+        let deadline = base_instant + Duration::from_secs_f64(offset.as_secs_f64() / scale);
+        (deadline > Instant::now()).then_some(deadline)
+    }
+}
+
+impl<R: BufRead + Unpin> Stream for ReplayStream<R> {
+    type Item = crate::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some((_, sleep)) = this.pending.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let (packet, _) = this.pending.take().unwrap();
+            return Poll::Ready(Some(Ok(packet)));
+        }
+        if this.finished {
+            return Poll::Ready(None);
+        }
+        loop {
+            let packet = match this.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    this.finished = true;
+                    return Poll::Ready(None);
+                }
+                Err(e) => {
+                    // JSON Lines 中单行解析失败可以跳过，其他错误之后不再继续读取
+                    let recoverable = matches!(this.source, Source::JsonLines { .. })
+                        && !matches!(e, crate::Error::Io(_));
+                    if !recoverable {
+                        this.finished = true;
+                    }
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            if let Some(time) = this.seek_to {
+                if packet.time < time {
+                    continue;
+                }
+                this.seek_to = None;
+            }
+            match this.deadline(&packet) {
+                None => return Poll::Ready(Some(Ok(packet))),
+                Some(deadline) => {
+                    let mut sleep = Box::pin(tokio::time::sleep_until(deadline));
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Some(Ok(packet)));
+                    }
+                    this.pending = Some((packet, sleep));
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{JsonLines, RecordSink};
+    use crate::ws_protocol::{KnownOperation, Operation};
+    use futures::StreamExt;
+
+    fn recorded(seconds: &[i64]) -> Vec<u8> {
+        let start = Local::now();
+        let mut sink = JsonLines::new(vec![], 5440).unwrap();
+        for (i, s) in seconds.iter().enumerate() {
+            let packet = Packet {
+                time: start + chrono::Duration::seconds(*s),
+                ..Packet::new(Operation::Unknown(9999), i.to_string(), 5440)
+            };
+            sink.write_packet(&packet).unwrap();
+        }
+        sink.into_inner()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pacing() {
+        let bytes = recorded(&[0, 10, 30]);
+        let start = Instant::now();
+        let replay = ReplayStream::json_lines(&bytes[..]).pacing(Pacing::Scaled(10.0));
+        let packets: Vec<_> = replay.map(|p| p.unwrap()).collect().await;
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[2].operation, Operation::Unknown(9999));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        let start = Instant::now();
+        let replay = ReplayStream::json_lines(&bytes[..]).pacing(Pacing::Unlimited);
+        assert_eq!(replay.count().await, 3);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_seek() {
+        let bytes = recorded(&[0, 10, 20, 30]);
+        let mut replay = ReplayStream::json_lines(&bytes[..]);
+        let first = replay.next().await.unwrap().unwrap();
+        assert_eq!(replay.header().unwrap().room_id, 5440);
+        replay.seek(first.time + chrono::Duration::seconds(15));
+
+        let start = Instant::now();
+        let bodies: Vec<_> = replay.map(|p| p.unwrap().body).collect().await;
+        assert_eq!(bodies, vec!["2", "3"]);
+        // 从跳转后的第一个包开始计时
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_seek_keeps_pending() {
+        let bytes = recorded(&[0, 10, 20]);
+        let mut replay = ReplayStream::json_lines(&bytes[..]);
+        let first = replay.next().await.unwrap().unwrap();
+        // "1" 已经读出，正在等待
+        assert!(futures::poll!(replay.next()).is_pending());
+        replay.seek(first.time + chrono::Duration::seconds(5));
+
+        let start = Instant::now();
+        let bodies: Vec<_> = replay.map(|p| p.unwrap().body).collect().await;
+        assert_eq!(bodies, vec!["1", "2"]);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_raw_frames() {
+        let mut bytes =
+            Packet::new(Operation::Known(KnownOperation::SendMsgReply), "a", 0).to_bytes();
+        bytes.extend(Packet::heartbeat().to_bytes());
+        // 没有录制时间，跳转不起作用
+        let mut replay = ReplayStream::raw_frames(&bytes[..], 5440);
+        replay.seek(Local::now() + chrono::Duration::days(1));
+        let packets: Vec<_> = replay.map(|p| p.unwrap()).collect().await;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].body, "a");
+        assert_eq!(packets[1].room_id, 5440);
+
+        let replay = ReplayStream::raw_frames(&bytes[..bytes.len() - 1], 5440);
+        let results: Vec<_> = replay.collect().await;
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+    }
+}
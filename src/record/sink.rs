@@ -0,0 +1,378 @@
+//! 录制的写入端
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use super::RecordHeader;
+use crate::ws_protocol::Packet;
+
+/// 录制的写入端，[`Recorder`][`super::Recorder`] 把收到的每个 packet 交给它
+pub trait RecordSink: Send {
+    /// 写入一个 packet，返回写入的字节数
+    fn write_packet(&mut self, packet: &Packet) -> crate::Result<usize>;
+
+    fn flush(&mut self) -> crate::Result<()>;
+
+    /// 结束录制，默认只 flush
+    fn finish(&mut self) -> crate::Result<()> {
+        self.flush()
+    }
+
+    /// 已经开始的分段数量
+    fn segments(&self) -> u64 {
+        1
+    }
+
+    /// [`Recorder`][`super::Recorder`] 把短号转换为长房号之后、写入之前调用，默认忽略
+    fn set_room_id(&mut self, _room_id: u64) {}
+}
+
+/// 写入任意的 [`Write`]，第一行为 [`RecordHeader`]，之后每行为一个 [`Packet`]
+pub struct JsonLines<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLines<W> {
+    /// 创建时立即写入 header
+    pub fn new(mut writer: W, room_id: u64) -> crate::Result<Self> {
+        write_line(&mut writer, &RecordHeader::new(room_id))?;
+        Ok(Self { writer })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> RecordSink for JsonLines<W> {
+    fn write_packet(&mut self, packet: &Packet) -> crate::Result<usize> {
+        write_line(&mut self.writer, packet)
+    }
+
+    fn flush(&mut self) -> crate::Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+fn write_line(writer: &mut impl Write, value: &impl serde::Serialize) -> crate::Result<usize> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(line.len())
+}
+
+/// 分段关闭之后的压缩方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// 不压缩，默认
+    #[default]
+    None,
+    /// 压缩为 `.jsonl.gz`
+    Gzip,
+    /// 压缩为 `.jsonl.zst`，需要启用 `zstd` feature
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// 不压缩时为 None
+    fn codec(self) -> Option<Codec> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some(Codec::Gzip),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Some(Codec::Zstd),
+        }
+    }
+}
+
+/// 实际使用的压缩算法
+#[derive(Debug, Clone, Copy)]
+enum Codec {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zst",
+        }
+    }
+
+    /// 压缩 path 到 `path.ext`，成功后删除原文件
+    fn compress(self, path: &Path) -> io::Result<PathBuf> {
+        let target = PathBuf::from(format!("{}.{}", path.display(), self.extension()));
+        let mut input = File::open(path)?;
+        let output = BufWriter::new(File::create(&target)?);
+        match self {
+            Self::Gzip => {
+                let mut w = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+                io::copy(&mut input, &mut w)?;
+                w.finish()?.flush()?;
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                let mut w = zstd::Encoder::new(output, 0)?;
+                io::copy(&mut input, &mut w)?;
+                w.finish()?.flush()?;
+            }
+        }
+        fs::remove_file(path)?;
+        Ok(target)
+    }
+}
+
+struct Segment {
+    path: PathBuf,
+    writer: JsonLines<BufWriter<File>>,
+    size: u64,
+    opened_at: Instant,
+}
+
+/// 按照大小或时间分段的文件写入端
+///
+/// 每个分段为目录下的 `{room_id}-{开始时间}-{序号}.jsonl`，都以 [`RecordHeader`] 开头。
+/// 分段关闭后在后台线程中压缩
+///
+/// ```no_run
+/// use biliapi::record::{Compression, RotatingFile};
+/// use std::time::Duration;
+///
+/// let sink = RotatingFile::new("records", 5440)
+///     .max_size(64 * 1024 * 1024)
+///     .max_age(Duration::from_secs(3600))
+///     .compression(Compression::Gzip);
+/// ```
+pub struct RotatingFile {
+    dir: PathBuf,
+    room_id: u64,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    compression: Compression,
+    current: Option<Segment>,
+    segments: u64,
+    compressing: Vec<JoinHandle<io::Result<PathBuf>>>,
+}
+
+impl RotatingFile {
+    /// 不分段、不压缩，目录不存在时会自动创建
+    pub fn new(dir: impl Into<PathBuf>, room_id: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            room_id,
+            max_size: None,
+            max_age: None,
+            compression: Compression::None,
+            current: None,
+            segments: 0,
+            compressing: vec![],
+        }
+    }
+
+    /// 分段超过这个大小（字节）之后开始新的分段
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// 分段超过这个时长之后开始新的分段
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// 分段关闭之后的压缩方式
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 正在写入的分段
+    pub fn current_path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|s| s.path.as_path())
+    }
+
+    fn should_rotate(&self, segment: &Segment) -> bool {
+        self.max_size.is_some_and(|max| segment.size >= max)
+            || self
+                .max_age
+                .is_some_and(|max| segment.opened_at.elapsed() >= max)
+    }
+
+    fn open_segment(&mut self) -> crate::Result<Segment> {
+        fs::create_dir_all(&self.dir)?;
+        let name = format!(
+            "{}-{}-{:04}.jsonl",
+            self.room_id,
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            self.segments
+        );
+        let path = self.dir.join(name);
+        debug!("opening record segment {:?}", path);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        self.segments += 1;
+        let mut writer = BufWriter::new(file);
+        // header 也计入分段的大小
+        let size = write_line(&mut writer, &RecordHeader::new(self.room_id))?;
+        Ok(Segment {
+            path,
+            writer: JsonLines { writer },
+            size: size as u64,
+            opened_at: Instant::now(),
+        })
+    }
+
+    /// 关闭当前的分段，并在后台开始压缩
+    fn close_segment(&mut self) -> crate::Result<()> {
+        let segment = match self.current.take() {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+        segment.writer.into_inner().flush()?;
+        if let Some(codec) = self.compression.codec() {
+            self.compressing.push(std::thread::spawn(move || {
+                codec.compress(&segment.path).inspect_err(|e| {
+                    warn!("failed to compress {:?}: {}", segment.path, e);
+                })
+            }));
+        }
+        self.compressing.retain(|handle| !handle.is_finished());
+        Ok(())
+    }
+}
+
+impl RecordSink for RotatingFile {
+    fn write_packet(&mut self, packet: &Packet) -> crate::Result<usize> {
+        if self.current.as_ref().is_some_and(|s| self.should_rotate(s)) {
+            self.close_segment()?;
+        }
+        if self.current.is_none() {
+            self.current = Some(self.open_segment()?);
+        }
+        let segment = self.current.as_mut().unwrap();
+        let n = segment.writer.write_packet(packet)?;
+        segment.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> crate::Result<()> {
+        match &mut self.current {
+            Some(segment) => segment.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// 关闭当前的分段，并等待所有的压缩完成
+    fn finish(&mut self) -> crate::Result<()> {
+        self.close_segment()?;
+        for handle in self.compressing.drain(..) {
+            match handle.join() {
+                Ok(result) => {
+                    result?;
+                }
+                Err(_) => warn!("compress thread panicked"),
+            }
+        }
+        Ok(())
+    }
+
+    fn segments(&self) -> u64 {
+        self.segments
+    }
+
+    /// 之后的分段文件名和 header 使用这个房号
+    fn set_room_id(&mut self, room_id: u64) {
+        self.room_id = room_id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws_protocol::{KnownOperation, Operation};
+    use std::io::{BufRead, BufReader, Read};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("biliapi-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn packet(body: &str) -> Packet {
+        Packet::new(Operation::Known(KnownOperation::SendMsgReply), body, 5440)
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut sink = JsonLines::new(vec![], 5440).unwrap();
+        sink.write_packet(&packet(r#"{"cmd":"A"}"#)).unwrap();
+        let bytes = sink.into_inner();
+        let lines: Vec<_> = bytes.lines().map(|l| l.unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        let header: RecordHeader = serde_json::from_str(&lines[0]).unwrap();
+        assert!(header.is_supported());
+        assert_eq!(header.room_id, 5440);
+        let p: Packet = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(p.body, r#"{"cmd":"A"}"#);
+    }
+
+    #[test]
+    fn test_rotate_and_compress() {
+        let dir = temp_dir("rotate");
+        let mut sink = RotatingFile::new(&dir, 5440)
+            .max_size(1)
+            .compression(Compression::Gzip);
+        for body in ["a", "b", "c"] {
+            sink.write_packet(&packet(body)).unwrap();
+        }
+        sink.finish().unwrap();
+        assert_eq!(sink.segments(), 3);
+
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 3);
+        for (file, body) in files.iter().zip(["a", "b", "c"]) {
+            assert_eq!(file.extension().unwrap(), "gz");
+            let mut content = String::new();
+            flate2::read::GzDecoder::new(File::open(file).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+            let mut lines = BufReader::new(content.as_bytes()).lines();
+            let header: RecordHeader =
+                serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            assert_eq!(header.room_id, 5440);
+            let p: Packet = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            assert_eq!(p.body, body);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_counts_header() {
+        let dir = temp_dir("rotate-header");
+        let p = packet("a");
+        let line = serde_json::to_vec(&p).unwrap().len() as u64 + 1;
+        // 只计算 packet 的话第二个 packet 之后才会分段
+        let mut sink = RotatingFile::new(&dir, 5440).max_size(line + 1);
+        sink.write_packet(&p).unwrap();
+        sink.write_packet(&p).unwrap();
+        sink.finish().unwrap();
+        assert_eq!(sink.segments(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
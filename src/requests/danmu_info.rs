@@ -23,17 +23,21 @@ impl DanmuServer {
     pub fn url(&self) -> String {
         format!("wss://{}:{}/sub", self.host, self.wss_port)
     }
+    /// 获取对应的 TCP 地址，形如 `tcp://host:port`
+    pub fn tcp_url(&self) -> String {
+        format!("tcp://{}:{}", self.host, self.port)
+    }
 }
 
 impl Request for DanmuInfo {
     type Args = u64;
+    const HOST: Host = Host::ApiLive;
 
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self> {
         const DANMU_INFO_URL: &str =
             "https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo";
 
-        let request = client.get(DANMU_INFO_URL).query(&[("id", args)]).send();
-        Box::pin(async move { request.await?.bili_data().await })
+        Self::send_data(client, client.get(DANMU_INFO_URL).query(&[("id", args)]))
     }
 }
 
@@ -42,10 +46,16 @@ mod tests {
     use crate::Request;
     use anyhow::*;
     #[tokio::test]
+    #[allow(clippy::len_zero)]
     async fn test_get_danmu_info() -> Result<()> {
-        let client = crate::connection::new_client()?;
-        let info = crate::requests::DanmuInfo::request(&client, 2).await?;
+        let server = crate::testing::MockServer::with_fixtures().await?;
+        let info = crate::requests::DanmuInfo::request(&server.client(), 2).await?;
         assert!(info.servers.len() > 0);
+        assert_eq!(
+            info.servers[0].url(),
+            "wss://zj-cn-live-comet.chat.bilibili.com:443/sub"
+        );
+        assert_eq!(server.received()[0].query("id"), Some("2"));
         Ok(())
     }
 }
@@ -1,6 +1,9 @@
 //! 跟登录有关的请求，只实现了扫码登录
 //!
-//! 登录流程：先请求一个二维码，然后提示给用户，然后发起 [`CheckQrLogin`] 的轮询
+//! 登录流程：先请求一个二维码，然后提示给用户，然后发起 [`CheckQrLogin`] 的轮询。
+//!
+//! [`QrLoginGenerate`] 和 [`QrLoginPoll`] 是新版的接口，一般直接使用
+//! [`QrLogin`][`crate::login::QrLogin`] 即可
 
 use crate::requests::prelude::*;
 
@@ -17,11 +20,10 @@ pub struct QrLoginRequest {
 
 impl Request for QrLoginRequest {
     type Args = ();
+    const HOST: Host = Host::Passport;
     fn request(client: &Client, _args: ()) -> RequestResponse<Self> {
         const URL: &str = "https://passport.bilibili.com/qrcode/getLoginUrl";
-        let request = client.get(URL).send();
-
-        Box::pin(async move { request.await?.bili_data().await })
+        Self::send_data(client, client.get(URL))
     }
 }
 
@@ -40,6 +42,7 @@ pub enum CheckQrLogin {
 }
 
 impl CheckQrLogin {
+    #[deprecated(note = "旧版接口已经停用，使用 `QrLoginPoll` 或者 `login::QrLogin`")]
     pub fn is_success(&self) -> Option<bool> {
         match self {
             // 错误或者超时，不可重试
@@ -58,10 +61,11 @@ impl CheckQrLogin {
 impl Request for CheckQrLogin {
     // 扫码登录秘钥
     type Args = String;
+    const HOST: Host = Host::Passport;
 
     fn request(client: &Client, oauth_key: String) -> RequestResponse<Self> {
         const URL: &str = "https://passport.bilibili.com/qrcode/getLoginInfo";
-        let request = client.post(URL).form(&[("oauthKey", oauth_key)]).send();
+        let request = client.send(client.post(URL).form(&[("oauthKey", oauth_key)]));
 
         /// 这玩意儿跟一般的返回结果不一样，特殊处理
         #[allow(unused)]
@@ -82,11 +86,71 @@ impl Request for CheckQrLogin {
     }
 }
 
+/// 新版扫码登录，申请一个二维码
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QrLoginGenerate {
+    /// 二维码内容 url
+    pub url: String,
+    /// 扫码登录秘钥，有效期 180 秒
+    pub qrcode_key: String,
+}
+
+impl Request for QrLoginGenerate {
+    type Args = ();
+    const HOST: Host = Host::Passport;
+
+    fn request(client: &Client, _args: ()) -> RequestResponse<Self> {
+        const URL: &str = "https://passport.bilibili.com/x/passport-login/web/qrcode/generate";
+        Self::send_data(client, client.get(URL))
+    }
+}
+
+/// 新版扫码登录，轮询扫码结果
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QrLoginPoll {
+    /// 0：成功
+    /// 86038：二维码已失效
+    /// 86090：已扫码未确认
+    /// 86101：未扫码
+    pub code: i64,
+    #[serde(default)]
+    pub message: String,
+    /// 成功时为带有 cookie 的跨域 url，否则为空
+    #[serde(default)]
+    pub url: String,
+    /// 成功时用于刷新 cookie 的 token，否则为空
+    #[serde(default)]
+    pub refresh_token: String,
+    /// 毫秒时间戳
+    #[serde(default)]
+    pub timestamp: i64,
+}
+
+impl Request for QrLoginPoll {
+    /// [`QrLoginGenerate::qrcode_key`]
+    type Args = String;
+    const HOST: Host = Host::Passport;
+
+    fn request(client: &Client, qrcode_key: String) -> RequestResponse<Self> {
+        const URL: &str = "https://passport.bilibili.com/x/passport-login/web/qrcode/poll";
+        Self::send_data(client, client.get(URL).query(&[("qrcode_key", qrcode_key)]))
+    }
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_get_qr_login_request() -> Result<()> {
-    let client = crate::connection::new_client()?;
+    let server = crate::testing::MockServer::with_fixtures().await?;
+    let client = server.client();
     let r = QrLoginRequest::request(&client, ()).await?;
-    dbg!(r);
+    assert!(r.url.ends_with(&r.oauth_key));
+
+    let check = CheckQrLogin::request(&client, r.oauth_key.clone()).await?;
+    #[allow(deprecated)]
+    let success = check.is_success();
+    assert_eq!(success, None);
+    let received = server.received();
+    assert_eq!(received[1].method, reqwest::Method::POST);
+    assert_eq!(received[1].body, format!("oauthKey={}", r.oauth_key));
     Ok(())
 }
@@ -1,10 +1,10 @@
 //! 基于 HTTP 的各种请求
 //!
 //! # Example
-//! ```
+//! ```no_run
 //! use biliapi::Request;
 //! # tokio_test::block_on(async {
-//! let client = biliapi::connection::new_client().unwrap();
+//! let client = biliapi::connection::Client::new().unwrap();
 //! let info = biliapi::requests::InfoByRoom::request(&client, 1).await.unwrap();
 //! // 拿到长房号
 //! assert_eq!(info.room_info.room_id, 5440);
@@ -12,11 +12,14 @@
 //! ```
 //!
 mod prelude {
-    pub use reqwest::{Client, Response, StatusCode};
+    pub use reqwest::{RequestBuilder, Response, StatusCode};
     pub use serde::de::DeserializeOwned;
     pub use std::{future::Future, pin::Pin};
 
-    pub use crate::{Error, Result};
+    pub use crate::{
+        connection::{Client, Host},
+        Error, Result,
+    };
 
     pub(super) use super::BiliResponseExt;
     pub use super::{Request, RequestResponse};
@@ -43,6 +46,8 @@ impl<T: DeserializeOwned> BiliResponse<T> {
     pub fn into_data(self) -> Option<T> {
         self.data
     }
+    // map_err 的写法兼容 inspect_err 稳定之前的 rustc
+    #[allow(clippy::manual_inspect)]
     pub async fn from_response(response: Response) -> Result<T> {
         if response.status() != StatusCode::OK {
             let status = response.status();
@@ -55,6 +60,7 @@ impl<T: DeserializeOwned> BiliResponse<T> {
 
             return Err(Error::StatusCode(status));
         }
+        let url = response.url().to_string();
         let response_text = response.text().await?;
         let this: Self = serde_json::from_str(&response_text).map_err(|e| {
             debug!("response text = {}", response_text);
@@ -63,8 +69,9 @@ impl<T: DeserializeOwned> BiliResponse<T> {
         if this.code != 0 {
             debug!("response text = {}", response_text);
             return Err(Error::BiliCustom {
-                code: this.code,
+                code: this.code.into(),
                 message: this.message,
+                url: Some(url),
             });
         }
         match this.data {
@@ -101,9 +108,9 @@ impl BiliResponseExt for Response {
 ///
 /// 所有对 bilibili 的请求都应该实现这个 trait，如
 /// ```no_run
-/// use biliapi::requests::{Request, BiliResponseExt, RequestResponse};
+/// use biliapi::requests::{Request, RequestResponse};
 /// use serde::Deserialize;
-/// use reqwest::Client;
+/// use biliapi::connection::Client;
 ///
 /// #[derive(Debug, Deserialize)]
 /// struct SomeApi {
@@ -112,14 +119,11 @@ impl BiliResponseExt for Response {
 /// impl Request for SomeApi {
 ///     type Args = i64;
 ///     fn request(client: &Client, args: i64) -> RequestResponse<Self> {
-///         let request = client.get("https://api.bilibili.com/some/api")
-///             .query(&[("id", args)])
-///             .send();
-///         Box::pin(async move {
-///             // 这里需要引入 `BiliResponseExt`
-///             request.await?.bili_data().await
-///         })
-///
+///         // 通过 `send_data` 发送才会等待频率限制，并按照 `WBI` 签名
+///         Self::send_data(
+///             client,
+///             client.get("https://api.bilibili.com/some/api").query(&[("id", args)]),
+///         )
 ///     }
 /// }
 /// ```
@@ -127,8 +131,46 @@ pub trait Request: DeserializeOwned {
     /// 请求对应的参数
     type Args;
 
-    /// 请求的实现
+    /// 请求发往的域名，重试时用于日志
+    const HOST: Host = Host::Api;
+
+    /// 是否需要 WBI 签名，默认不需要。为 true 时 [`send_data`][`Request::send_data`]
+    /// 会自动签名，见 [`wbi`]
+    const WBI: bool = false;
+
+    /// 请求的实现，会等待 client 上设置的频率限制，但是不会重试
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self>;
+
+    /// 发送 `request` 并解析 data，[`WBI`][`Request::WBI`] 为 true 时先签名。
+    ///
+    /// 实现 [`request`][`Request::request`] 时都应该通过它发送。`T` 一般就是 `Self`，
+    /// 也可以是包着 `Self` 的中间结构
+    fn send_data<T>(client: &Client, request: RequestBuilder) -> RequestResponse<T>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        if Self::WBI {
+            return request.wbi_data(client);
+        }
+        let response = client.send(request);
+        Box::pin(async move { response.await?.bili_data().await })
+    }
+
+    /// 同 [`request`][`Request::request`]，但是会按照
+    /// [`RetryPolicy`][`crate::connection::RetryPolicy`] 重试
+    fn request_with_retry(client: &Client, args: Self::Args) -> RequestResponse<Self>
+    where
+        Self: Send + 'static,
+        Self::Args: Clone + Send + Sync + 'static,
+    {
+        let client = client.clone();
+        Box::pin(async move {
+            crate::connection::with_retry(&client, Self::HOST, || {
+                Self::request(&client, args.clone())
+            })
+            .await
+        })
+    }
 }
 /// [`Request`] trait 返回结果的封装，本质就是 `Pin<Box<dyn Future<Output = Result<T>>>>`
 pub type RequestResponse<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
@@ -143,7 +185,7 @@ mod video_info;
 pub use video_info::{VideoInfo, VideoPage, VideoStat};
 
 mod login;
-pub use login::{CheckQrLogin, QrLoginRequest};
+pub use login::{CheckQrLogin, QrLoginGenerate, QrLoginPoll, QrLoginRequest};
 
 mod uploader_stat;
 pub use uploader_stat::UploaderStat;
@@ -156,3 +198,6 @@ pub use vote_info::VoteInfo;
 
 mod my_account_info;
 pub use my_account_info::MyAccountInfo;
+
+pub mod wbi;
+pub use wbi::{WbiExt, WbiKeys};
@@ -6,13 +6,13 @@ use crate::requests::prelude::*;
 pub struct MyAccountInfo {
     /// uid
     #[serde(rename = "mid")]
-    uid: u64,
+    pub uid: u64,
 
     #[serde(rename = "uname")]
-    username: String,
+    pub username: String,
 
     /// 签名
-    sign: String,
+    pub sign: String,
 }
 
 impl Request for MyAccountInfo {
@@ -20,7 +20,27 @@ impl Request for MyAccountInfo {
 
     fn request(client: &Client, _: Self::Args) -> RequestResponse<Self> {
         const URL: &str = "https://api.bilibili.com/x/member/web/account";
-        let r = client.get(URL).send();
-        Box::pin(async move { r.await?.bili_data().await })
+        Self::send_data(client, client.get(URL))
     }
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_my_account_info() -> Result<()> {
+    use crate::testing::{Mock, MockResponse, MockServer};
+
+    let server = MockServer::with_fixtures().await?;
+    let info = MyAccountInfo::request(&server.client(), ()).await?;
+    assert_eq!(info.uid, 12345678);
+    assert_eq!(info.username, "biliapi");
+
+    server.mount(
+        Mock::get(Host::Api, "/x/member/web/account")
+            .respond(MockResponse::bili_error(-101, "账号未登录")),
+    );
+    let e = MyAccountInfo::request(&server.client(), ())
+        .await
+        .unwrap_err();
+    assert!(e.is_not_logged_in());
+    Ok(())
+}
@@ -41,25 +41,32 @@ pub struct AnchorBaseInfo {
 
 impl Request for InfoByRoom {
     type Args = u64;
+    const HOST: Host = Host::ApiLive;
 
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self> {
         const ROOM_INIT_URL: &str =
             "https://api.live.bilibili.com/xlive/web-room/v1/index/getInfoByRoom";
 
-        let request = client.get(ROOM_INIT_URL).query(&[("room_id", args)]).send();
-
-        Box::pin(async { request.await?.bili_data().await })
+        Self::send_data(
+            client,
+            client.get(ROOM_INIT_URL).query(&[("room_id", args)]),
+        )
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Request;
+    use crate::{
+        connection::Host,
+        testing::{Mock, MockResponse, MockServer},
+        Request,
+    };
     use anyhow::*;
 
     #[tokio::test]
     async fn test_get_cgg_room_info() -> Result<()> {
-        let client = crate::connection::new_client()?;
+        let server = MockServer::with_fixtures().await?;
+        let client = server.client();
         // 超果果
         let info = crate::requests::InfoByRoom::request(&client, 646).await?;
         assert_eq!(info.room_info.room_id, 21133);
@@ -71,12 +78,22 @@ mod tests {
 
         assert_eq!(info.anchor_info.base.uname, "超果果mc");
         assert_eq!(info.anchor_info.base.gender, "男");
+
+        let received = server.received();
+        assert_eq!(received[0].query("room_id"), Some("646"));
+        assert_eq!(received[1].query("room_id"), Some("21133"));
         Ok(())
     }
 
     #[tokio::test]
     async fn test_get_non_exist_room_info() -> Result<()> {
-        let client = crate::connection::new_client()?;
+        let server = MockServer::with_fixtures().await?;
+        server.mount(
+            Mock::get(Host::ApiLive, "/xlive/web-room/v1/index/getInfoByRoom")
+                .query("room_id", 38)
+                .respond(MockResponse::bili_error(19002000, "获取初始化数据失败")),
+        );
+        let client = server.client();
         let info = crate::requests::InfoByRoom::request(&client, 38)
             .await
             .err()
@@ -85,6 +102,7 @@ mod tests {
             info.to_string(),
             "Bilibili error: (19002000) 获取初始化数据失败"
         );
+        assert_eq!(info.bili_code(), Some(crate::BiliErrorCode::RoomNotFound));
         Ok(())
     }
 }
@@ -43,8 +43,7 @@ impl Request for UploaderStat {
     type Args = u64;
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self> {
         const URL: &str = "https://api.bilibili.com/x/space/upstat";
-        let r = client.get(URL).query(&[("mid", args)]).send();
-        Box::pin(async move { r.await?.bili_data().await })
+        Self::send_data(client, client.get(URL).query(&[("mid", args)]))
     }
 }
 
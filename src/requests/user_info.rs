@@ -28,24 +28,23 @@ impl Request for UserInfo {
 
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self> {
         const URL: &str = "https://api.bilibili.com/x/web-interface/card";
-        let r = client.get(URL).query(&[("mid", args)]).send();
+        let r = Self::send_data::<Outer>(client, client.get(URL).query(&[("mid", args)]));
 
         #[derive(Debug, Deserialize)]
         struct Outer {
             card: UserInfo,
         }
 
-        Box::pin(async move {
-            let outer: Outer = r.await?.bili_data().await?;
-            Ok(outer.card)
-        })
+        Box::pin(async move { Ok(r.await?.card) })
     }
 }
 
 #[cfg(test)]
 #[tokio::test]
 async fn test_user_info() {
-    let client = crate::connection::new_client().unwrap();
-    let info = UserInfo::request(&client, 672328094).await.unwrap();
+    let server = crate::testing::MockServer::with_fixtures().await.unwrap();
+    let info = UserInfo::request(&server.client(), 672328094)
+        .await
+        .unwrap();
     assert_eq!(info.name, "嘉然今天吃什么");
 }
@@ -99,16 +99,15 @@ impl Request for VideoInfo {
 
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self> {
         const URL: &str = "https://api.bilibili.com/x/web-interface/view";
-        let request = client.get(URL).query(&[("bvid", &args)]).send();
-        Box::pin(async move { request.await?.bili_data().await })
+        Self::send_data(client, client.get(URL).query(&[("bvid", &args)]))
     }
 }
 
 #[cfg(test)]
 #[tokio::test]
 async fn test_video_info() -> Result<()> {
-    let client = crate::connection::new_client()?;
-    let info = VideoInfo::request(&client, "BV1QB4y1u7Jj".to_string()).await?;
+    let server = crate::testing::MockServer::with_fixtures().await?;
+    let info = VideoInfo::request(&server.client(), "BV1QB4y1u7Jj".to_string()).await?;
     assert_eq!(info.aid, 588385189);
     assert!(info.stat.like > 4100);
     assert!(info.title.contains("亲爱的那不是爱情"));
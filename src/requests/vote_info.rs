@@ -25,21 +25,21 @@ pub struct VoteInfo {
 impl Request for VoteInfo {
     // vote id
     type Args = u64;
+    const HOST: Host = Host::ApiVc;
     fn request(client: &Client, args: Self::Args) -> RequestResponse<Self> {
-        let r = client
-            .get("https://api.vc.bilibili.com/vote_svr/v1/vote_svr/vote_info")
-            .query(&[("vote_id", args)])
-            .send();
+        let r = Self::send_data::<Helper>(
+            client,
+            client
+                .get("https://api.vc.bilibili.com/vote_svr/v1/vote_svr/vote_info")
+                .query(&[("vote_id", args)]),
+        );
 
         #[derive(Debug, Deserialize)]
         struct Helper {
             pub info: VoteInfo,
         }
 
-        Box::pin(async move {
-            let helper: Helper = r.await?.bili_data().await?;
-            Ok(helper.info)
-        })
+        Box::pin(async move { Ok(r.await?.info) })
     }
 }
 
@@ -51,3 +51,15 @@ pub struct VoteOption {
     pub idx: u32,
     pub title: String,
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_vote_info() -> Result<()> {
+    let server = crate::testing::MockServer::with_fixtures().await?;
+    let info = VoteInfo::request(&server.client(), 1807411).await?;
+    assert_eq!(info.vote_id, 1807411);
+    assert_eq!(info.options.len(), 2);
+    assert_eq!(info.cnt, info.options.iter().map(|o| o.cnt).sum::<u64>());
+    assert_eq!(server.received()[0].query("vote_id"), Some("1807411"));
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! WBI 签名
+//!
+//! `api.bilibili.com` 的部分接口需要在 query 中带上 `w_rid` 和 `wts`，否则会返回 -403 或 -352。
+//! 签名用的 `img_key` 和 `sub_key` 从 nav 接口获取，缓存在 [`Client`] 上，每天会轮换。
+//!
+//! 需要签名的 [`Request`] 实现声明 [`WBI`][`Request::WBI`] 为 true，
+//! [`Request::send_data`] 发送时就会自动签名；其他请求可以使用 [`WbiExt::wbi_data`]：
+//! ```no_run
+//! use biliapi::requests::{Request, RequestResponse};
+//! use biliapi::connection::Client;
+//! use serde::Deserialize;
+//!
+//! #[derive(Debug, Deserialize)]
+//! struct SpaceInfo {
+//!     pub name: String,
+//! }
+//! impl Request for SpaceInfo {
+//!     type Args = u64;
+//!     const WBI: bool = true;
+//!     fn request(client: &Client, mid: u64) -> RequestResponse<Self> {
+//!         Self::send_data(
+//!             client,
+//!             client
+//!                 .get("https://api.bilibili.com/x/space/wbi/acc/info")
+//!                 .query(&[("mid", mid)]),
+//!         )
+//!     }
+//! }
+//! ```
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use futures::lock::Mutex;
+use reqwest::Url;
+
+use super::{prelude::*, BiliResponse};
+use crate::BiliErrorCode;
+
+const NAV_URL: &str = "https://api.bilibili.com/x/web-interface/nav";
+
+/// key 的缓存时间，过期之后重新从 nav 接口获取
+const KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+const MIXIN_KEY_ENC_TAB: [usize; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
+    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
+    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
+];
+
+/// nav 接口返回的 `img_key` 和 `sub_key`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WbiKeys {
+    pub img_key: String,
+    pub sub_key: String,
+}
+
+impl WbiKeys {
+    pub fn new(img_key: impl Into<String>, sub_key: impl Into<String>) -> Self {
+        Self {
+            img_key: img_key.into(),
+            sub_key: sub_key.into(),
+        }
+    }
+
+    /// 从 `wbi_img` 中的 `img_url` 和 `sub_url` 解析，key 为图片的文件名
+    pub fn from_urls(img_url: &str, sub_url: &str) -> Option<Self> {
+        fn key(url: &str) -> Option<&str> {
+            let name = url.rsplit('/').next()?;
+            let key = name.split('.').next()?;
+            (!key.is_empty()).then_some(key)
+        }
+        Some(Self::new(key(img_url)?, key(sub_url)?))
+    }
+
+    /// 把 `img_key + sub_key` 按照固定的表打乱之后取前 32 位
+    pub fn mixin_key(&self) -> String {
+        let raw: Vec<char> = self.img_key.chars().chain(self.sub_key.chars()).collect();
+        MIXIN_KEY_ENC_TAB
+            .iter()
+            .filter_map(|&i| raw.get(i))
+            .take(32)
+            .collect()
+    }
+
+    /// 对参数签名，返回带上 `wts` 和 `w_rid` 的 query string
+    pub fn sign<K, V>(&self, params: impl IntoIterator<Item = (K, V)>, wts: i64) -> String
+    where
+        K: Into<String>,
+        V: AsRef<str>,
+    {
+        let mut params: Vec<(String, String)> = params
+            .into_iter()
+            .map(|(k, v)| {
+                let v = v.as_ref().chars().filter(|c| !"!'()*".contains(*c));
+                (k.into(), v.collect())
+            })
+            .filter(|(k, _)| k != "w_rid" && k != "wts")
+            .collect();
+        params.push(("wts".to_string(), wts.to_string()));
+        params.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let query = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", encode(k), encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let w_rid = md5::compute(format!("{}{}", query, self.mixin_key()));
+        format!("{}&w_rid={:x}", query, w_rid)
+    }
+
+    /// 对 url 中已有的 query 签名
+    pub fn sign_url(&self, url: &mut Url, wts: i64) {
+        let query = self.sign(url.query_pairs().into_owned(), wts);
+        url.set_query(Some(&query));
+    }
+}
+
+/// 同 js 的 `encodeURIComponent`
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Deserialize)]
+struct WbiImg {
+    img_url: String,
+    sub_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Nav {
+    wbi_img: WbiImg,
+}
+
+/// [`WbiKeys`] 的缓存，每个 [`Client`] 一份
+#[derive(Debug, Default)]
+pub(crate) struct WbiCache {
+    keys: RwLock<Option<(WbiKeys, Instant)>>,
+    /// 同时只有一个请求去获取 key，其他的等待它的结果
+    fetching: Mutex<()>,
+}
+
+impl WbiCache {
+    fn cached(&self) -> Option<WbiKeys> {
+        match self.keys.read().unwrap().as_ref() {
+            Some((keys, fetched_at)) if fetched_at.elapsed() < KEY_TTL => Some(keys.clone()),
+            _ => None,
+        }
+    }
+
+    /// 获取 key，没有缓存或者过期时从 nav 接口获取
+    pub(crate) async fn keys(&self, client: &Client) -> Result<WbiKeys> {
+        if let Some(keys) = self.cached() {
+            return Ok(keys);
+        }
+        let _guard = self.fetching.lock().await;
+        // 等待期间其他请求可能已经获取到了
+        if let Some(keys) = self.cached() {
+            return Ok(keys);
+        }
+        // 未登录时 code 为 -101，但是 data 里面仍然有 wbi_img
+        let response = client.send(client.get(NAV_URL)).await?;
+        if response.status() != StatusCode::OK {
+            return Err(Error::StatusCode(response.status()));
+        }
+        let nav: BiliResponse<Nav> = response.json().await?;
+        let img = nav.into_data().ok_or(Error::DataNotFound)?.wbi_img;
+        let keys = WbiKeys::from_urls(&img.img_url, &img.sub_url).ok_or(Error::DataNotFound)?;
+        debug!("wbi keys refreshed: {:?}", keys);
+        *self.keys.write().unwrap() = Some((keys.clone(), Instant::now()));
+        Ok(keys)
+    }
+
+    /// 丢弃缓存的 key，下次使用时重新获取。
+    ///
+    /// 只有缓存的还是 `used` 时才丢弃，避免并发失败的请求把刚刚刷新的 key 也丢掉
+    pub(crate) fn invalidate(&self, used: &WbiKeys) {
+        let mut keys = self.keys.write().unwrap();
+        if keys.as_ref().is_some_and(|(cached, _)| cached == used) {
+            keys.take();
+        }
+    }
+}
+
+/// 为 [`RequestBuilder`] 加上 WBI 签名
+pub trait WbiExt {
+    /// 签名之后发送请求并解析 data。
+    ///
+    /// 返回 -352 时认为 key 已经轮换，会重新获取 key 再请求一次
+    fn wbi_data<T: DeserializeOwned + Send + 'static>(self, client: &Client) -> RequestResponse<T>;
+}
+
+impl WbiExt for RequestBuilder {
+    fn wbi_data<T: DeserializeOwned + Send + 'static>(self, client: &Client) -> RequestResponse<T> {
+        let client = client.clone();
+        Box::pin(async move {
+            let mut request = self.build()?;
+            let mut refreshed = false;
+            loop {
+                let keys = client.wbi().keys(&client).await?;
+                let retry = request.try_clone();
+                keys.sign_url(request.url_mut(), chrono::Utc::now().timestamp());
+                let result = client.execute(request).await?.bili_data().await;
+                match (result, retry) {
+                    (Err(e), Some(retry))
+                        if !refreshed && e.bili_code() == Some(BiliErrorCode::RiskControl) =>
+                    {
+                        debug!("wbi signed request failed: {}, refresh keys", e);
+                        client.wbi().invalidate(&keys);
+                        refreshed = true;
+                        request = retry;
+                    }
+                    (result, _) => return result,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> WbiKeys {
+        WbiKeys::from_urls(
+            "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png",
+            "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mixin_key() {
+        let keys = keys();
+        assert_eq!(keys.img_key, "7cd084941338484aae1ad9425b84077c");
+        assert_eq!(keys.sub_key, "4932caff0ff746eab6f01bf08b70ac45");
+        assert_eq!(keys.mixin_key(), "ea1db124af3c7062474693fa704f4ff8");
+    }
+
+    #[test]
+    fn test_sign() {
+        let query = keys().sign(
+            [("foo", "114"), ("bar", "514"), ("zab", "1919810")],
+            1702204169,
+        );
+        assert_eq!(
+            query,
+            "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wbi_data() -> Result<()> {
+        use crate::testing::{Mock, MockResponse, MockServer};
+
+        let server = MockServer::with_fixtures().await?;
+        server.mount(
+            Mock::get(Host::Api, "/x/space/wbi/acc/info")
+                .respond_once(MockResponse::bili_error(-352, "风控校验失败"))
+                .respond(MockResponse::data(serde_json::json!({ "name": "biliapi" }))),
+        );
+        let client = server.client();
+        let data: serde_json::Value = client
+            .get("https://api.bilibili.com/x/space/wbi/acc/info")
+            .query(&[("mid", 12345678)])
+            .wbi_data(&client)
+            .await?;
+        assert_eq!(data["name"], "biliapi");
+
+        // -352 之后重新获取了 key
+        let received = server.received();
+        let paths: Vec<_> = received.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/x/web-interface/nav",
+                "/x/space/wbi/acc/info",
+                "/x/web-interface/nav",
+                "/x/space/wbi/acc/info"
+            ]
+        );
+        let signed = &received[3];
+        assert_eq!(signed.query("mid"), Some("12345678"));
+        let wts: i64 = signed.query("wts").unwrap().parse().unwrap();
+        let expected = keys().sign([("mid", "12345678")], wts);
+        assert!(expected.ends_with(signed.query("w_rid").unwrap()));
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct SpaceInfo {
+        name: String,
+    }
+
+    impl Request for SpaceInfo {
+        type Args = u64;
+        const WBI: bool = true;
+        fn request(client: &Client, mid: u64) -> RequestResponse<Self> {
+            Self::send_data(
+                client,
+                client
+                    .get("https://api.bilibili.com/x/space/wbi/acc/info")
+                    .query(&[("mid", mid)]),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_declared_wbi() -> Result<()> {
+        use crate::testing::{Mock, MockResponse, MockServer};
+
+        let server = MockServer::with_fixtures().await?;
+        server.mount(
+            Mock::get(Host::Api, "/x/space/wbi/acc/info")
+                .respond(MockResponse::data(serde_json::json!({ "name": "biliapi" }))),
+        );
+        // nav 接口变慢时，并发的请求只获取一次 key
+        server.mount(Mock::get(Host::Api, "/x/web-interface/nav").respond(
+            MockResponse::json(crate::testing::fixtures::NAV).delay(Duration::from_millis(50)),
+        ));
+        let client = server.client();
+        let (a, b) = futures::join!(
+            SpaceInfo::request(&client, 1),
+            SpaceInfo::request(&client, 2)
+        );
+        assert_eq!((a?.name, b?.name), ("biliapi".into(), "biliapi".into()));
+
+        let received = server.received();
+        let navs = received
+            .iter()
+            .filter(|r| r.path == "/x/web-interface/nav")
+            .count();
+        assert_eq!(navs, 1);
+        assert!(received
+            .iter()
+            .filter(|r| r.path == "/x/space/wbi/acc/info")
+            .all(|r| r.query("w_rid").is_some()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_keeps_keys() -> Result<()> {
+        use crate::testing::{Mock, MockResponse, MockServer};
+
+        let server = MockServer::with_fixtures().await?;
+        server.mount(
+            Mock::get(Host::Api, "/x/space/wbi/acc/info")
+                .respond(MockResponse::bili_error(-403, "访问权限不足")),
+        );
+        let client = server.client();
+        let e = SpaceInfo::request(&client, 1).await.unwrap_err();
+        assert_eq!(e.bili_code(), Some(BiliErrorCode::Forbidden));
+        // -403 不会重新获取 key
+        let paths: Vec<_> = server.received().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, ["/x/web-interface/nav", "/x/space/wbi/acc/info"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_url() {
+        let mut url = Url::parse("https://api.bilibili.com/x/space/wbi/search?mid=1&keyword=%E4%BD%A0+%E5%A5%BD!&wts=1&w_rid=old").unwrap();
+        keys().sign_url(&mut url, 1702204169);
+        assert_eq!(
+            url.query(),
+            Some("keyword=%E4%BD%A0%20%E5%A5%BD&mid=1&wts=1702204169&w_rid=6eea5dd3b4b6f1decb9ca179ca495753")
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! 保存在文件中的登录状态
+//!
+//! [`Session`] 持有一个 [`Client`] 和它使用的 cookie，可以从文件加载、保存到文件，
+//! 这样登录一次之后就不需要每次都扫码。
+//!
+//! # Example
+//! ```no_run
+//! use biliapi::{requests::UploaderStat, session::Session, Request};
+//!
+//! # async fn dox() -> biliapi::Result<()> {
+//! // 文件不存在时得到一个空的 session
+//! let session = Session::load("./persisted_cookies.json")?;
+//! if !session.is_valid().await? {
+//!     // 扫码登录 ...
+//! }
+//! let stat = UploaderStat::request(session.client(), 282994).await?;
+//! session.save()?;
+//! # Ok(()) }
+//! ```
+use std::{
+    fs,
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use reqwest::Url;
+
+pub use cookie_store::CookieStore;
+pub use reqwest_cookie_store::CookieStoreMutex;
+
+use crate::{
+    connection::{Client, ClientBuilder},
+    requests::MyAccountInfo,
+    Request, Result,
+};
+
+/// 登录 cookie 所在的域名
+const COOKIE_URL: &str = "https://www.bilibili.com/";
+
+fn invalid_data(e: impl std::fmt::Display) -> crate::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string()).into()
+}
+
+/// 带 cookie 的 client，见 [模块文档][`self`]
+pub struct Session {
+    client: Client,
+    cookies: Arc<CookieStoreMutex>,
+    path: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 不输出 cookie 的内容
+        f.debug_struct("Session")
+            .field("client", &self.client)
+            .field("path", &self.path)
+            .field("logged_in", &self.is_logged_in())
+            .finish()
+    }
+}
+
+impl Session {
+    /// 没有任何 cookie 的 session，不关联文件
+    pub fn new() -> Result<Self> {
+        Self::with_cookies(CookieStore::default(), ClientBuilder::new())
+    }
+
+    /// 使用 `builder` 的配置创建 client，cookie 相关的设置会被覆盖
+    pub fn with_cookies(cookies: CookieStore, builder: ClientBuilder) -> Result<Self> {
+        let cookies = Arc::new(CookieStoreMutex::new(cookies));
+        let client = builder.cookie_provider(cookies.clone()).build()?;
+        Ok(Self {
+            client,
+            cookies,
+            path: None,
+        })
+    }
+
+    /// 从文件加载，文件不存在时得到一个空的 session。之后 [`save`][`Session::save`] 会写回这个文件
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with(path, ClientBuilder::new())
+    }
+
+    /// 同 [`load`][`Session::load`]，使用 `builder` 的配置创建 client
+    pub fn load_with(path: impl AsRef<Path>, builder: ClientBuilder) -> Result<Self> {
+        let path = path.as_ref();
+        let cookies = match fs::File::open(path) {
+            Ok(file) => CookieStore::load_json(BufReader::new(file)).map_err(invalid_data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("cookie file {:?} not found, start with empty session", path);
+                CookieStore::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut session = Self::with_cookies(cookies, builder)?;
+        session.path = Some(path.to_path_buf());
+        Ok(session)
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// client 使用的 cookie store
+    pub fn cookies(&self) -> Arc<CookieStoreMutex> {
+        self.cookies.clone()
+    }
+
+    /// 关联的文件
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 保存到加载时的文件，没有关联文件时返回错误
+    pub fn save(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => self.save_to(path),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "session is not associated with a file",
+            )
+            .into()),
+        }
+    }
+
+    /// 保存到 `path`：先写入同目录下的临时文件再重命名，unix 下文件权限为 0600
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut buffer = vec![];
+        self.cookies
+            .lock()
+            .unwrap()
+            .save_json(&mut buffer)
+            .map_err(invalid_data)?;
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // 临时文件已经存在时 mode 不生效
+            if tmp.exists() {
+                fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        let mut file = options.open(&tmp)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        debug!("session saved to {:?}", path);
+        Ok(())
+    }
+
+    fn cookie(&self, name: &str) -> Option<String> {
+        let url = Url::parse(COOKIE_URL).unwrap();
+        let cookies = self.cookies.lock().unwrap();
+        let value = cookies
+            .get_request_values(&url)
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.to_string());
+        value
+    }
+
+    /// 登录凭证 `SESSDATA`
+    pub fn sessdata(&self) -> Option<String> {
+        self.cookie("SESSDATA")
+    }
+
+    /// `bili_jct`，即 POST 请求需要的 csrf token
+    pub fn bili_jct(&self) -> Option<String> {
+        self.cookie("bili_jct")
+    }
+
+    /// `DedeUserID`，即登录账号的 uid
+    pub fn dede_user_id(&self) -> Option<u64> {
+        self.cookie("DedeUserID")?.parse().ok()
+    }
+
+    /// 是否有登录的 cookie，不检查是否已经失效，需要检查时使用 [`is_valid`][`Session::is_valid`]
+    pub fn is_logged_in(&self) -> bool {
+        self.sessdata().is_some()
+    }
+
+    /// 当前登录的账号
+    pub async fn account(&self) -> Result<MyAccountInfo> {
+        MyAccountInfo::request(&self.client, ()).await
+    }
+
+    /// 通过 [`MyAccountInfo`] 检查登录是否有效，未登录或者登录失效时返回 `Ok(false)`
+    pub async fn is_valid(&self) -> Result<bool> {
+        match self.account().await {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_not_logged_in() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 清除所有的 cookie，即退出登录
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::Host,
+        testing::{Mock, MockResponse, MockServer},
+    };
+
+    fn login(session: &Session) {
+        let url = Url::parse(COOKIE_URL).unwrap();
+        let mut cookies = session.cookies.lock().unwrap();
+        for cookie in [
+            "SESSDATA=abc%2C123; Domain=.bilibili.com; Path=/; Max-Age=15552000; HttpOnly; Secure",
+            "bili_jct=0123456789abcdef; Domain=.bilibili.com; Path=/; Max-Age=15552000",
+            "DedeUserID=12345678; Domain=.bilibili.com; Path=/; Max-Age=15552000",
+        ] {
+            cookies.parse(cookie, &url).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("biliapi-session-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cookies.json");
+
+        let session = Session::load(&path).unwrap();
+        assert!(!session.is_logged_in());
+        login(&session);
+        assert_eq!(session.sessdata().as_deref(), Some("abc%2C123"));
+        assert_eq!(session.bili_jct().as_deref(), Some("0123456789abcdef"));
+        assert_eq!(session.dede_user_id(), Some(12345678));
+        session.save().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let loaded = Session::load(&path).unwrap();
+        assert_eq!(loaded.dede_user_id(), Some(12345678));
+        loaded.clear();
+        assert!(!loaded.is_logged_in());
+        assert!(Session::new().unwrap().save().is_err());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Session::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_is_valid() {
+        let server = MockServer::with_fixtures().await.unwrap();
+        let session =
+            Session::with_cookies(CookieStore::default(), server.client_builder()).unwrap();
+        assert!(session.is_valid().await.unwrap());
+        assert_eq!(session.account().await.unwrap().username, "biliapi");
+
+        server.mount(
+            Mock::get(Host::Api, "/x/member/web/account")
+                .respond(MockResponse::bili_error(-101, "账号未登录")),
+        );
+        assert!(!session.is_valid().await.unwrap());
+
+        server.mount(
+            Mock::get(Host::Api, "/x/member/web/account")
+                .respond(MockResponse::bili_error(-412, "请求被拦截")),
+        );
+        assert!(session.is_valid().await.unwrap_err().is_rate_limited());
+    }
+}
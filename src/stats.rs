@@ -0,0 +1,529 @@
+//! 直播间数据统计，从 [`Packet`] 中计算弹幕数、礼物收入等指标
+//!
+//! 统计使用包的时间（[`Packet::time`]），所以对重放的录制同样有效
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    mem,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Timelike};
+
+use crate::ws_protocol::{KnownOperation, LiveEvent, Operation, Packet, ParseError};
+
+/// 按照礼物名称统计的礼物
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GiftSummary {
+    /// 礼物数量
+    pub num: u64,
+    /// 总价，单位见 coin_type
+    pub coin: u64,
+    /// gold（金瓜子）或者 silver（银瓜子）
+    pub coin_type: String,
+}
+
+/// 一段时间内的统计结果
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSummary {
+    pub danmaku: u64,
+    /// 发送过弹幕的不同用户数，人数较多时为估算值，见 [`LiveStats`]
+    pub chatters: u64,
+    /// 按礼物名称统计
+    pub gifts: BTreeMap<String, GiftSummary>,
+    /// 按 coin_type 统计的礼物总价，gold 时 1000 对应 1 元
+    pub gift_coin: BTreeMap<String, u64>,
+    pub super_chats: u64,
+    /// 醒目留言总价，单位为元
+    pub super_chat_price: u64,
+    /// 按大航海等级统计的上舰数量（月），1 总督，2 提督，3 舰长
+    pub guards: BTreeMap<u32, u64>,
+    /// 上舰总价，单位为金瓜子
+    pub guard_price: u64,
+    /// 心跳回复中的最高人气值
+    pub peak_popularity: Option<u32>,
+    /// 最近一次 WATCHED_CHANGE 中看过的人数
+    pub watched: Option<u64>,
+}
+
+/// 每分钟的弹幕统计
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinuteStats {
+    pub start: DateTime<Local>,
+    pub danmaku: u64,
+    pub chatters: u64,
+}
+
+/// [`LiveStats`] 的快照，用于导出
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    /// 第一个包的时间
+    pub since: Option<DateTime<Local>>,
+    /// 最后一个包的时间
+    pub until: Option<DateTime<Local>>,
+    pub cumulative: StatsSummary,
+    /// 最近一段时间的统计
+    pub window: StatsSummary,
+    pub window_secs: u64,
+    pub per_minute: Vec<MinuteStats>,
+}
+
+/// 统计的中间结果，需要保留用户 id 来计算去重后的人数
+#[derive(Debug, Clone, Default)]
+struct Aggregate {
+    summary: StatsSummary,
+    chatters: Chatters,
+}
+
+/// 精确计数的最大人数，超过之后改用 HyperLogLog 估算
+const EXACT_CHATTERS: usize = 1024;
+/// HyperLogLog 的寄存器数量为 2^12，标准误差约 1.6%
+const HLL_BITS: u32 = 12;
+const HLL_REGISTERS: usize = 1 << HLL_BITS;
+
+/// 去重的用户计数，人数少时保存 uid，人数多时只占用固定的内存
+#[derive(Debug, Clone)]
+enum Chatters {
+    Exact(HashSet<u64>),
+    Sketch(Box<[u8]>),
+}
+
+impl Default for Chatters {
+    fn default() -> Self {
+        Self::Exact(HashSet::new())
+    }
+}
+
+impl Chatters {
+    fn insert(&mut self, uid: u64) {
+        match self {
+            Self::Exact(uids) => {
+                uids.insert(uid);
+                if uids.len() > EXACT_CHATTERS {
+                    self.sketch();
+                }
+            }
+            Self::Sketch(registers) => hll_insert(registers, uid),
+        }
+    }
+
+    fn merge(&mut self, other: &Chatters) {
+        match other {
+            Self::Exact(uids) => uids.iter().for_each(|uid| self.insert(*uid)),
+            Self::Sketch(other) => {
+                let registers = self.sketch();
+                for (r, o) in registers.iter_mut().zip(other.iter()) {
+                    *r = (*r).max(*o);
+                }
+            }
+        }
+    }
+
+    fn sketch(&mut self) -> &mut [u8] {
+        if let Self::Exact(uids) = self {
+            let mut registers = vec![0; HLL_REGISTERS].into_boxed_slice();
+            for uid in mem::take(uids) {
+                hll_insert(&mut registers, uid);
+            }
+            *self = Self::Sketch(registers);
+        }
+        match self {
+            Self::Sketch(registers) => registers,
+            Self::Exact(_) => unreachable!(),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        let registers = match self {
+            Self::Exact(uids) => return uids.len() as u64,
+            Self::Sketch(registers) => registers,
+        };
+        let m = HLL_REGISTERS as f64;
+        let sum: f64 = registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = registers.iter().filter(|r| **r == 0).count();
+        // 人数较少时使用线性计数修正
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+fn hll_insert(registers: &mut [u8], uid: u64) {
+    // splitmix64，保证 uid 的各个位都被打散
+    let mut h = uid.wrapping_add(0x9E3779B97F4A7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D049BB133111EB);
+    h ^= h >> 31;
+
+    let idx = (h >> (64 - HLL_BITS)) as usize;
+    let rank = ((h << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() as u8 + 1;
+    registers[idx] = registers[idx].max(rank);
+}
+
+impl Aggregate {
+    fn merge(&mut self, other: &Aggregate) {
+        let (s, o) = (&mut self.summary, &other.summary);
+        s.danmaku += o.danmaku;
+        for (name, gift) in &o.gifts {
+            let entry = s.gifts.entry(name.clone()).or_default();
+            entry.num += gift.num;
+            entry.coin += gift.coin;
+            entry.coin_type.clone_from(&gift.coin_type);
+        }
+        for (coin_type, coin) in &o.gift_coin {
+            *s.gift_coin.entry(coin_type.clone()).or_default() += coin;
+        }
+        s.super_chats += o.super_chats;
+        s.super_chat_price += o.super_chat_price;
+        for (level, num) in &o.guards {
+            *s.guards.entry(*level).or_default() += num;
+        }
+        s.guard_price += o.guard_price;
+        s.peak_popularity = s.peak_popularity.max(o.peak_popularity);
+        // 按时间顺序合并，后面的覆盖前面的
+        s.watched = o.watched.or(s.watched);
+        self.chatters.merge(&other.chatters);
+    }
+
+    fn summary(&self) -> StatsSummary {
+        StatsSummary {
+            chatters: self.chatters.len(),
+            ..self.summary.clone()
+        }
+    }
+
+    fn add_event(&mut self, event: &LiveEvent) {
+        let s = &mut self.summary;
+        match event {
+            LiveEvent::Danmaku(danmaku) => {
+                s.danmaku += 1;
+                self.chatters.insert(danmaku.sender.uid);
+            }
+            LiveEvent::Gift(gift) => {
+                let coin = match gift.total_coin {
+                    0 => gift.price * gift.num,
+                    total => total,
+                };
+                let entry = s.gifts.entry(gift.gift_name.clone()).or_default();
+                entry.num += gift.num;
+                entry.coin += coin;
+                entry.coin_type.clone_from(&gift.coin_type);
+                *s.gift_coin.entry(gift.coin_type.clone()).or_default() += coin;
+            }
+            LiveEvent::SuperChat(sc) => {
+                s.super_chats += 1;
+                s.super_chat_price += sc.price;
+            }
+            LiveEvent::GuardBuy(guard) => {
+                *s.guards.entry(guard.guard_level).or_default() += guard.num;
+                s.guard_price += guard.price * guard.num;
+            }
+            LiveEvent::WatchedChange(watched) => s.watched = Some(watched.num),
+            _ => {}
+        }
+    }
+}
+
+/// 直播间数据统计，同时保留累计结果和按分钟划分的最近结果
+///
+/// 弹幕人数在 1024 人以内是精确的，超过之后使用 HyperLogLog 估算，误差约 1.6%，
+/// 每分钟的统计最多占用 4KB。
+///
+/// # Example
+/// ```no_run
+/// # use biliapi::connection::LiveConnection;
+/// use biliapi::stats::LiveStats;
+/// use futures::StreamExt;
+/// use std::time::Duration;
+/// # let (url, room_id, token) = ("", 1, "".to_string());
+/// # tokio_test::block_on(async {
+/// let mut con = LiveConnection::new(url, room_id, token).await.unwrap();
+/// let mut stats = LiveStats::new();
+/// while let Some(packet) = con.next().await {
+///     stats.push(&packet.unwrap()).ok();
+///     let last_5_minutes = stats.window(Duration::from_secs(300));
+///     println!("{}", serde_json::to_string(&last_5_minutes).unwrap());
+/// }
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct LiveStats {
+    retention: Duration,
+    cumulative: Aggregate,
+    /// 每分钟一个，按时间顺序
+    minutes: VecDeque<(DateTime<Local>, Aggregate)>,
+    since: Option<DateTime<Local>>,
+    until: Option<DateTime<Local>>,
+}
+
+impl Default for LiveStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveStats {
+    /// 保留最近一小时的分钟统计
+    pub fn new() -> Self {
+        Self::with_retention(Duration::from_secs(3600))
+    }
+
+    /// 保留最近 `retention` 的分钟统计，更早的只计入累计结果
+    pub fn with_retention(retention: Duration) -> Self {
+        Self {
+            retention,
+            cumulative: Aggregate::default(),
+            minutes: VecDeque::new(),
+            since: None,
+            until: None,
+        }
+    }
+
+    /// 统计一个包，心跳回复和 SendMsgReply 以外的包会被忽略
+    ///
+    /// 只会解析统计用到的 cmd，其余的包只读取 cmd 后跳过
+    pub fn push(&mut self, packet: &Packet) -> Result<(), ParseError> {
+        if packet.operation == Operation::Known(KnownOperation::HeartbeatReply) {
+            // 旧的录制中没有 popularity，只有 body
+            let popularity = packet.popularity.or_else(|| packet.body.parse().ok());
+            let time = packet.time;
+            self.update(time, |agg| {
+                agg.summary.peak_popularity = agg.summary.peak_popularity.max(popularity)
+            });
+            return Ok(());
+        }
+        let needed = packet.event_cmd().is_some_and(|cmd| {
+            matches!(
+                cmd.split(':').next(),
+                Some(
+                    "DANMU_MSG"
+                        | "SEND_GIFT"
+                        | "SUPER_CHAT_MESSAGE"
+                        | "GUARD_BUY"
+                        | "WATCHED_CHANGE"
+                )
+            )
+        });
+        if !needed {
+            self.touch(packet.time);
+            return Ok(());
+        }
+        match packet.decode_event()? {
+            Some(event) => self.push_event(packet.time, &event),
+            None => self.touch(packet.time),
+        }
+        Ok(())
+    }
+
+    /// 统计一个已经解析的事件，time 为收到的时间
+    pub fn push_event(&mut self, time: DateTime<Local>, event: &LiveEvent) {
+        self.update(time, |agg| agg.add_event(event));
+    }
+
+    fn touch(&mut self, time: DateTime<Local>) {
+        self.since = Some(self.since.map_or(time, |since| since.min(time)));
+        self.until = Some(self.until.map_or(time, |until| until.max(time)));
+    }
+
+    fn update(&mut self, time: DateTime<Local>, f: impl Fn(&mut Aggregate)) {
+        self.touch(time);
+        f(&mut self.cumulative);
+
+        let minute = truncate_minute(time);
+        // 一般都是最后一分钟，乱序的包插入到对应的位置
+        let idx = match self.minutes.iter().rposition(|(start, _)| *start <= minute) {
+            Some(idx) if self.minutes[idx].0 == minute => idx,
+            Some(idx) => {
+                self.minutes.insert(idx + 1, (minute, Aggregate::default()));
+                idx + 1
+            }
+            None => {
+                self.minutes.push_front((minute, Aggregate::default()));
+                0
+            }
+        };
+        f(&mut self.minutes[idx].1);
+
+        if let Some(until) = self.until {
+            let retention =
+                chrono::Duration::from_std(self.retention).unwrap_or(chrono::Duration::MAX);
+            while let Some((start, _)) = self.minutes.front() {
+                if until - *start <= retention {
+                    break;
+                }
+                self.minutes.pop_front();
+            }
+        }
+    }
+
+    /// 累计的统计结果
+    pub fn cumulative(&self) -> StatsSummary {
+        self.cumulative.summary()
+    }
+
+    /// 最后一个包之前 `duration` 内的统计结果，精确到分钟，不超过保留的时间
+    pub fn window(&self, duration: Duration) -> StatsSummary {
+        let until = match self.until {
+            Some(until) => until,
+            None => return StatsSummary::default(),
+        };
+        let from = truncate_minute(
+            until - chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX),
+        );
+        let mut agg = Aggregate::default();
+        for (_, minute) in self.minutes.iter().filter(|(start, _)| *start >= from) {
+            agg.merge(minute);
+        }
+        agg.summary()
+    }
+
+    /// 保留的每分钟弹幕统计，没有数据的分钟不会出现
+    pub fn per_minute(&self) -> Vec<MinuteStats> {
+        self.minutes
+            .iter()
+            .map(|(start, agg)| MinuteStats {
+                start: *start,
+                danmaku: agg.summary.danmaku,
+                chatters: agg.chatters.len(),
+            })
+            .collect()
+    }
+
+    /// 导出当前的统计结果，window 见 [`window`][`LiveStats::window`]
+    pub fn snapshot(&self, window: Duration) -> StatsSnapshot {
+        StatsSnapshot {
+            since: self.since,
+            until: self.until,
+            cumulative: self.cumulative(),
+            window: self.window(window),
+            window_secs: window.as_secs(),
+            per_minute: self.per_minute(),
+        }
+    }
+}
+
+fn truncate_minute(time: DateTime<Local>) -> DateTime<Local> {
+    time.with_second(0)
+        .and_then(|t| t.with_nanosecond(0))
+        .unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn packet(secs: i64, body: &str) -> Packet {
+        Packet {
+            time: Local.timestamp_opt(1650000000 + secs, 0).unwrap(),
+            ..Packet::new(Operation::Known(KnownOperation::SendMsgReply), body, 1)
+        }
+    }
+
+    fn danmaku(secs: i64, uid: u64) -> Packet {
+        let body = format!(
+            r#"{{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1650000000123,0,0,"",0,0,0,"",0,"{{}}","{{}}",{{}}],"hi",[{},"u",0,0,0,10000,1,""],[],[0,0,0,"",0],["",""],0,0,null,{{}},0,0,null,null,0,105]}}"#,
+            uid
+        );
+        packet(secs, &body)
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = LiveStats::with_retention(Duration::from_secs(180));
+        // 1650000000 是某一分钟的第 0 秒
+        for p in [
+            danmaku(0, 1),
+            danmaku(10, 1),
+            danmaku(70, 2),
+            packet(
+                80,
+                r#"{"cmd":"SEND_GIFT","data":{"coin_type":"gold","giftId":1,"giftName":"小花花","num":3,"price":100,"timestamp":1650000000,"total_coin":300,"uid":1,"uname":"a"}}"#,
+            ),
+            packet(
+                90,
+                r#"{"cmd":"WATCHED_CHANGE","data":{"num":1234,"text_large":"1234人看过"}}"#,
+            ),
+            danmaku(200, 3),
+        ] {
+            stats.push(&p).unwrap();
+        }
+        let heartbeat = Packet {
+            popularity: Some(42),
+            ..Packet::new(Operation::Known(KnownOperation::HeartbeatReply), "42", 1)
+        };
+        stats
+            .push(&Packet {
+                time: packet(100, "").time,
+                ..heartbeat
+            })
+            .unwrap();
+
+        let cumulative = stats.cumulative();
+        assert_eq!(cumulative.danmaku, 4);
+        assert_eq!(cumulative.chatters, 3);
+        assert_eq!(cumulative.gifts["小花花"].num, 3);
+        assert_eq!(cumulative.gift_coin["gold"], 300);
+        assert_eq!(cumulative.watched, Some(1234));
+        assert_eq!(cumulative.peak_popularity, Some(42));
+
+        // 第 0 分钟超出了保留时间
+        let minutes = stats.per_minute();
+        assert_eq!(
+            minutes.iter().map(|m| m.danmaku).collect::<Vec<_>>(),
+            vec![1, 1]
+        );
+        let window = stats.window(Duration::from_secs(60));
+        assert_eq!((window.danmaku, window.chatters), (1, 1));
+        assert_eq!(window.gift_coin.get("gold"), None);
+        let window = stats.window(Duration::from_secs(3600));
+        assert_eq!((window.danmaku, window.chatters), (2, 2));
+        assert_eq!(window.peak_popularity, Some(42));
+
+        let snapshot = stats.snapshot(Duration::from_secs(60));
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<StatsSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn test_skip_unused_cmds() {
+        let mut stats = LiveStats::new();
+        // 不需要的 cmd 格式不对也不影响
+        stats
+            .push(&packet(0, r#"{"cmd":"INTERACT_WORD","data":[]}"#))
+            .unwrap();
+        stats.push(&danmaku(1, 1)).unwrap();
+        assert_eq!(stats.cumulative().danmaku, 1);
+        assert_eq!(stats.since, Some(packet(0, "").time));
+    }
+
+    #[test]
+    fn test_chatters_estimate() {
+        let mut exact = Chatters::default();
+        let mut other = Chatters::default();
+        for uid in 0..EXACT_CHATTERS as u64 {
+            exact.insert(uid);
+            other.insert(uid + 500);
+        }
+        assert!(matches!(exact, Chatters::Exact(_)));
+        assert_eq!(exact.len(), EXACT_CHATTERS as u64);
+
+        // 合并后超过上限，改为估算
+        exact.merge(&other);
+        assert!(matches!(exact, Chatters::Sketch(_)));
+        let n = exact.len() as f64;
+        assert!((n - 1524.0).abs() < 1524.0 * 0.05, "{}", n);
+
+        for uid in 0..100_000 {
+            exact.insert(uid);
+        }
+        let n = exact.len() as f64;
+        assert!((n - 100_000.0).abs() < 100_000.0 * 0.05, "{}", n);
+    }
+}
@@ -0,0 +1,33 @@
+//! 测试工具，需要启用 `testing` feature
+//!
+//! [`MockServer`] 在本地代替 b 站的 API，可以返回录制的 [`fixtures`]，也可以按顺序返回指定的错误码、状态码，
+//! 不需要连接网络就可以测试 [`Request`][`crate::Request`]。
+//!
+//! 同时启用 `live` feature 时，`MockLiveServer` 可以代替直播间的弹幕服务器，
+//! 用来测试认证、心跳、解压、断线重连和看门狗。
+//!
+//! # Example
+//! ```
+//! use biliapi::{connection::Host, requests::InfoByRoom, Request};
+//! use biliapi::testing::{Mock, MockResponse, MockServer};
+//! # tokio_test::block_on(async {
+//! let server = MockServer::with_fixtures().await.unwrap();
+//! server.mount(
+//!     Mock::get(Host::ApiLive, "/xlive/web-room/v1/index/getInfoByRoom")
+//!         .query("room_id", 38)
+//!         .respond(MockResponse::bili_error(19002000, "获取初始化数据失败")),
+//! );
+//! let client = server.client();
+//!
+//! let info = InfoByRoom::request(&client, 646).await.unwrap();
+//! assert_eq!(info.room_info.room_id, 21133);
+//! assert!(InfoByRoom::request(&client, 38).await.is_err());
+//! # });
+//! ```
+pub mod fixtures;
+mod http;
+pub use http::{Mock, MockResponse, MockServer, ReceivedRequest};
+#[cfg(feature = "live")]
+mod live;
+#[cfg(feature = "live")]
+pub use live::{LiveScript, MockLiveServer};
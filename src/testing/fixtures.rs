@@ -0,0 +1,65 @@
+//! 录制下来的 API 返回结果
+//!
+//! 对应一个已经登录的账号，[`mount`] 会把它们挂载到各自的路由上
+use super::{Mock, MockResponse, MockServer};
+use crate::connection::Host;
+
+/// `getInfoByRoom`，超果果的直播间（21133，短号 646）
+pub const ROOM_INFO: &str = include_str!("fixtures/room_info.json");
+/// `getDanmuInfo`
+pub const DANMU_INFO: &str = include_str!("fixtures/danmu_info.json");
+/// `qrcode/getLoginUrl`
+pub const QR_LOGIN_URL: &str = include_str!("fixtures/qr_login_url.json");
+/// `qrcode/getLoginInfo`，还没有扫码
+pub const QR_LOGIN_INFO: &str = include_str!("fixtures/qr_login_info.json");
+/// `x/passport-login/web/qrcode/generate`
+pub const QR_GENERATE: &str = include_str!("fixtures/qr_generate.json");
+/// `x/passport-login/web/qrcode/poll`，还没有扫码
+pub const QR_POLL: &str = include_str!("fixtures/qr_poll.json");
+/// `x/web-interface/card`，mid 672328094
+pub const USER_CARD: &str = include_str!("fixtures/user_card.json");
+/// `x/web-interface/view`，BV1QB4y1u7Jj
+pub const VIDEO_VIEW: &str = include_str!("fixtures/video_view.json");
+/// `x/web-interface/nav`，包括 wbi 签名用的 key
+pub const NAV: &str = include_str!("fixtures/nav.json");
+/// `x/member/web/account`
+pub const ACCOUNT: &str = include_str!("fixtures/account.json");
+/// `x/space/upstat`
+pub const UPSTAT: &str = include_str!("fixtures/upstat.json");
+/// `vote_svr/v1/vote_svr/vote_info`
+pub const VOTE_INFO: &str = include_str!("fixtures/vote_info.json");
+
+/// 所有 fixture 对应的 (域名, 路径, 内容)
+pub const ROUTES: &[(Host, &str, &str)] = &[
+    (
+        Host::ApiLive,
+        "/xlive/web-room/v1/index/getInfoByRoom",
+        ROOM_INFO,
+    ),
+    (
+        Host::ApiLive,
+        "/xlive/web-room/v1/index/getDanmuInfo",
+        DANMU_INFO,
+    ),
+    (Host::Passport, "/qrcode/getLoginUrl", QR_LOGIN_URL),
+    (Host::Passport, "/qrcode/getLoginInfo", QR_LOGIN_INFO),
+    (
+        Host::Passport,
+        "/x/passport-login/web/qrcode/generate",
+        QR_GENERATE,
+    ),
+    (Host::Passport, "/x/passport-login/web/qrcode/poll", QR_POLL),
+    (Host::Api, "/x/web-interface/card", USER_CARD),
+    (Host::Api, "/x/web-interface/view", VIDEO_VIEW),
+    (Host::Api, "/x/web-interface/nav", NAV),
+    (Host::Api, "/x/member/web/account", ACCOUNT),
+    (Host::Api, "/x/space/upstat", UPSTAT),
+    (Host::ApiVc, "/vote_svr/v1/vote_svr/vote_info", VOTE_INFO),
+];
+
+/// 把所有 fixture 挂载到 `server` 上
+pub fn mount(server: &MockServer) {
+    for (host, path, body) in ROUTES {
+        server.mount(Mock::new(*host, *path).respond(MockResponse::json(*body)));
+    }
+}
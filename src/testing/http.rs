@@ -0,0 +1,436 @@
+//! 本地的 http 服务器，代替 b 站的 API
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::{Method, StatusCode, Url};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::connection::{Client, ClientBuilder, Host};
+
+/// 服务器返回的内容
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: String,
+    delay: Option<Duration>,
+}
+
+impl MockResponse {
+    /// 原样返回 json，一般是录制下来的 fixture
+    pub fn json(body: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::OK,
+            headers: vec![],
+            body: body.into(),
+            delay: None,
+        }
+    }
+
+    /// 返回成功的结果，`data` 会被包在 `{"code":0,"message":"0","data":...}` 中
+    pub fn data(data: serde_json::Value) -> Self {
+        Self::json(
+            serde_json::json!({
+                "code": 0,
+                "message": "0",
+                "ttl": 1,
+                "data": data,
+            })
+            .to_string(),
+        )
+    }
+
+    /// 返回 b 站的错误码，如 `-412`、`19002000`
+    pub fn bili_error(code: i64, message: &str) -> Self {
+        Self::json(
+            serde_json::json!({
+                "code": code,
+                "message": message,
+                "ttl": 1,
+            })
+            .to_string(),
+        )
+    }
+
+    /// 只返回状态码，没有 body
+    pub fn status(status: StatusCode) -> Self {
+        Self::json("").with_status(status)
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// 添加一个响应头，如 `Set-Cookie`，可以多次调用
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// 等待一段时间之后再返回，用于测试超时
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    async fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or(""),
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(self.body.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+/// 一条路由规则，匹配 host、路径、方法和 query 参数
+///
+/// 先按顺序返回 [`respond_once`][`Mock::respond_once`] 设置的结果，用完之后一直返回
+/// [`respond`][`Mock::respond`] 设置的结果；都没有时交给更早挂载的规则处理
+#[derive(Debug, Clone)]
+pub struct Mock {
+    host: Host,
+    method: Option<Method>,
+    path: String,
+    query: Vec<(String, String)>,
+    scripted: VecDeque<MockResponse>,
+    response: Option<MockResponse>,
+}
+
+impl Mock {
+    /// 匹配任意方法
+    pub fn new(host: Host, path: impl Into<String>) -> Self {
+        Self {
+            host,
+            method: None,
+            path: path.into(),
+            query: vec![],
+            scripted: VecDeque::new(),
+            response: None,
+        }
+    }
+
+    pub fn get(host: Host, path: impl Into<String>) -> Self {
+        Self::new(host, path).method(Method::GET)
+    }
+
+    pub fn post(host: Host, path: impl Into<String>) -> Self {
+        Self::new(host, path).method(Method::POST)
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// 要求 query 中有这个参数，可以多次调用
+    pub fn query(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.query.push((key.into(), value.to_string()));
+        self
+    }
+
+    /// 之后每次都返回 `response`
+    pub fn respond(mut self, response: MockResponse) -> Self {
+        self.response = Some(response);
+        self
+    }
+
+    /// 只返回一次 `response`，多次调用时按顺序返回
+    pub fn respond_once(mut self, response: MockResponse) -> Self {
+        self.scripted.push_back(response);
+        self
+    }
+
+    fn matches(&self, request: &ReceivedRequest) -> bool {
+        Some(self.host) == request.host
+            && self.path == request.path
+            && self.method.as_ref().is_none_or(|m| *m == request.method)
+            && self.query.iter().all(|q| request.query.contains(q))
+    }
+
+    fn next_response(&mut self) -> Option<MockResponse> {
+        self.scripted.pop_front().or_else(|| self.response.clone())
+    }
+}
+
+/// 服务器收到的请求
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub method: Method,
+    /// 请求发往的域名，不是通过 [`MockServer::client`] 发出的请求为 None
+    pub host: Option<Host>,
+    /// 去掉域名前缀之后的路径，如 `/x/web-interface/nav`
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl ReceivedRequest {
+    /// query 中某个参数的值
+    pub fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 某个请求头的值，不区分大小写
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    mocks: Vec<Mock>,
+    received: Vec<ReceivedRequest>,
+}
+
+impl State {
+    fn respond(&mut self, request: ReceivedRequest) -> MockResponse {
+        let response = self
+            .mocks
+            .iter_mut()
+            .rev()
+            .filter(|mock| mock.matches(&request))
+            .find_map(|mock| mock.next_response());
+        let response = response.unwrap_or_else(|| {
+            warn!(
+                "no mock for {} {:?} {}",
+                request.method, request.host, request.path
+            );
+            MockResponse::bili_error(-404, "啥都木有").with_status(StatusCode::NOT_FOUND)
+        });
+        self.received.push(request);
+        response
+    }
+}
+
+/// 监听在本地的 http 服务器，按照挂载的 [`Mock`] 返回结果。drop 时停止
+///
+/// 每个域名的请求都被转发到 `http://127.0.0.1:port/{域名}/` 下，所以 [`client`][`MockServer::client`]
+/// 发出的请求路径和真实的 API 一致
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// 启动一个没有任何路由的服务器
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let task = tokio::spawn(Self::serve(listener, state.clone()));
+        Ok(Self { addr, state, task })
+    }
+
+    /// 启动服务器并挂载所有录制的 [`fixtures`][`super::fixtures`]
+    pub async fn with_fixtures() -> io::Result<Self> {
+        let server = Self::start().await?;
+        super::fixtures::mount(&server);
+        Ok(server)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `host` 对应的地址，可以传给 [`ClientBuilder::base_url`]
+    pub fn base_url(&self, host: Host) -> Url {
+        Url::parse(&format!("http://{}/{}/", self.addr, host.domain())).unwrap()
+    }
+
+    /// 所有域名都指向这个服务器的 [`ClientBuilder`]，可以继续设置其他选项
+    pub fn client_builder(&self) -> ClientBuilder {
+        Host::ALL
+            .into_iter()
+            .fold(Client::builder(), |builder, host| {
+                builder.base_url(host, self.base_url(host))
+            })
+    }
+
+    pub fn client(&self) -> Client {
+        self.client_builder()
+            .build()
+            .expect("failed to build http client")
+    }
+
+    /// 挂载一条路由，后挂载的优先匹配
+    pub fn mount(&self, mock: Mock) {
+        self.state.lock().unwrap().mocks.push(mock);
+    }
+
+    /// 到目前为止收到的所有请求
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    async fn serve(listener: TcpListener, state: Arc<Mutex<State>>) {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("mock server failed to accept: {}", e);
+                    continue;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle(stream, state).await {
+                    debug!("mock server connection error: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(mut stream: TcpStream, state: Arc<Mutex<State>>) -> io::Result<()> {
+        let request = read_request(&mut stream).await?;
+        trace!("mock server received {} {}", request.method, request.path);
+        let response = state.lock().unwrap().respond(request);
+        response.write_to(&mut stream).await
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<ReceivedRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(invalid_data("bad request line")),
+    };
+    let method = Method::from_bytes(method.as_bytes()).map_err(|_| invalid_data("bad method"))?;
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    let url = Url::parse(&format!("http://localhost{}", target))
+        .map_err(|_| invalid_data("bad request target"))?;
+    let (host, path) = match url.path()[1..].split_once('/') {
+        Some((domain, rest)) => match Host::from_domain(domain) {
+            Some(host) => (Some(host), format!("/{}", rest)),
+            None => (None, url.path().to_string()),
+        },
+        None => (None, url.path().to_string()),
+    };
+    Ok(ReceivedRequest {
+        method,
+        host,
+        path,
+        query: url.query_pairs().into_owned().collect(),
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_server() {
+        let server = MockServer::start().await.unwrap();
+        server.mount(
+            Mock::get(Host::Api, "/x/test")
+                .respond_once(MockResponse::bili_error(-412, "请求被拦截"))
+                .respond(MockResponse::data(serde_json::json!({ "value": 1 }))),
+        );
+        server.mount(
+            Mock::get(Host::Api, "/x/test")
+                .query("id", 2)
+                .respond(MockResponse::status(StatusCode::TOO_MANY_REQUESTS)),
+        );
+        let client = server.client();
+        let get = |id: u64| {
+            client
+                .get("https://api.bilibili.com/x/test")
+                .query(&[("id", id)])
+                .send()
+        };
+
+        let text = get(1).await.unwrap().text().await.unwrap();
+        assert!(text.contains("-412"));
+        let text = get(1).await.unwrap().text().await.unwrap();
+        assert!(text.contains(r#""value":1"#));
+        assert_eq!(
+            get(2).await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let response = client
+            .post("https://api.live.bilibili.com/not/found")
+            .form(&[("key", "value")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let received = server.received();
+        assert_eq!(received.len(), 4);
+        assert_eq!(received[0].host, Some(Host::Api));
+        assert_eq!(received[0].path, "/x/test");
+        assert_eq!(received[2].query("id"), Some("2"));
+        assert_eq!(received[3].method, Method::POST);
+        assert_eq!(received[3].host, Some(Host::ApiLive));
+        assert_eq!(received[3].body, "key=value");
+        assert!(received[3]
+            .header("user-agent")
+            .unwrap()
+            .starts_with("biliapi/"));
+    }
+}
@@ -0,0 +1,509 @@
+//! 本地的弹幕服务器，代替 b 站的直播间
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_tungstenite::tungstenite::Message as WsMessage;
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    stream::BoxStream,
+    SinkExt, StreamExt,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_util::codec::FramedRead;
+
+use super::MockResponse;
+use crate::ws_protocol::{AuthBody, KnownOperation, Operation, Packet, PacketDecoder};
+
+#[derive(Debug, Clone)]
+enum Action {
+    Frame(Vec<u8>),
+    Wait(Duration),
+    Drop,
+    Stall,
+}
+
+/// 连接认证成功之后服务器按顺序执行的动作
+///
+/// # Example
+/// ```
+/// use biliapi::testing::LiveScript;
+/// use biliapi::ws_protocol::{KnownOperation, Operation, Packet};
+/// use std::time::Duration;
+///
+/// let danmaku = Packet::new(Operation::Known(KnownOperation::SendMsgReply), r#"{"cmd":"DANMU_MSG"}"#, 0);
+/// let script = LiveScript::new()
+///     .batch(&[danmaku.clone(), danmaku], 2)
+///     .wait(Duration::from_millis(100))
+///     .drop_connection();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LiveScript {
+    actions: Vec<Action>,
+}
+
+impl LiveScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 发送一个未压缩的 packet
+    pub fn send(self, packet: &Packet) -> Self {
+        self.raw(packet.to_bytes())
+    }
+
+    /// 把多个 packet 压缩成一帧发送，`ver` 为 2（zlib）或 3（brotli）
+    pub fn batch(self, packets: &[Packet], ver: u16) -> Self {
+        self.raw(Packet::compress(packets, ver))
+    }
+
+    /// 原样发送一帧数据，可以用来测试解码出错
+    pub fn raw(mut self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.actions.push(Action::Frame(bytes.into()));
+        self
+    }
+
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.actions.push(Action::Wait(duration));
+        self
+    }
+
+    /// 直接断开连接，不发送 close 帧
+    pub fn drop_connection(mut self) -> Self {
+        self.actions.push(Action::Drop);
+        self
+    }
+
+    /// 之后不再发送任何数据，也不回复心跳，但是保持连接
+    pub fn stall(mut self) -> Self {
+        self.actions.push(Action::Stall);
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    scripts: VecDeque<LiveScript>,
+    popularity: u32,
+    token: Option<String>,
+    connections: usize,
+    auths: Vec<AuthBody>,
+    received: Vec<Packet>,
+}
+
+enum Outgoing {
+    Frame(Vec<u8>),
+    Drop,
+}
+
+type WsStream =
+    async_tungstenite::WebSocketStream<async_tungstenite::tokio::TokioAdapter<TcpStream>>;
+
+enum Writer {
+    WebSocket(futures::stream::SplitSink<WsStream, WsMessage>),
+    Tcp(tokio::net::tcp::OwnedWriteHalf),
+}
+
+impl Writer {
+    async fn send(&mut self, bytes: Vec<u8>) -> crate::Result<()> {
+        match self {
+            Self::WebSocket(sink) => sink.send(WsMessage::Binary(bytes)).await?,
+            Self::Tcp(write) => write.write_all(&bytes).await?,
+        }
+        Ok(())
+    }
+}
+
+/// 监听在本地的弹幕服务器，同时支持 websocket 和原始 TCP。drop 时停止
+///
+/// 每个连接的第一个包必须是合法的 auth 包，之后服务器会回复 AuthReply、执行一个 [`LiveScript`]，
+/// 并回复心跳（带上人气值）和切换房间的请求
+pub struct MockLiveServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockLiveServer {
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            popularity: 1,
+            ..Default::default()
+        }));
+        let task = tokio::spawn(Self::serve(listener, state.clone()));
+        Ok(Self { addr, state, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// websocket 的地址，如 `ws://127.0.0.1:12345/sub`
+    pub fn url(&self) -> String {
+        format!("ws://{}/sub", self.addr)
+    }
+
+    /// 原始 TCP 的地址，如 `tcp://127.0.0.1:12345`
+    pub fn tcp_url(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    /// 指向这个服务器的 `getDanmuInfo` 返回结果，可以挂载到 [`MockServer`][`super::MockServer`] 上。
+    ///
+    /// wss 端口同样指向这个服务器，但是不支持 TLS，需要使用
+    /// [`Transport::Tcp`][`crate::connection::Transport::Tcp`]
+    pub fn danmu_info(&self, token: &str) -> MockResponse {
+        let port = self.addr.port();
+        MockResponse::data(serde_json::json!({
+            "token": token,
+            "host_list": [{
+                "host": self.addr.ip().to_string(),
+                "port": port,
+                "wss_port": port,
+                "ws_port": port,
+            }],
+        }))
+    }
+
+    /// 只接受使用这个 token 的 auth，其他的回复 code -101 并断开
+    pub fn expect_token(&self, token: impl Into<String>) {
+        self.state.lock().unwrap().token = Some(token.into());
+    }
+
+    /// 心跳回复中的人气值，默认为 1
+    pub fn set_popularity(&self, popularity: u32) {
+        self.state.lock().unwrap().popularity = popularity;
+    }
+
+    /// 添加一个脚本，每个认证成功的连接按顺序使用一个，用完之后的连接只回复心跳
+    pub fn push_script(&self, script: LiveScript) {
+        self.state.lock().unwrap().scripts.push_back(script);
+    }
+
+    /// 到目前为止建立的连接数
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// 收到的所有合法的 auth 包的内容
+    pub fn auths(&self) -> Vec<AuthBody> {
+        self.state.lock().unwrap().auths.clone()
+    }
+
+    /// 收到的所有 packet，包括 auth 和心跳
+    pub fn received(&self) -> Vec<Packet> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    async fn serve(listener: TcpListener, state: Arc<Mutex<State>>) {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("mock live server failed to accept: {}", e);
+                    continue;
+                }
+            };
+            state.lock().unwrap().connections += 1;
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle(stream, state).await {
+                    debug!("mock live server connection error: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(stream: TcpStream, state: Arc<Mutex<State>>) -> crate::Result<()> {
+        // websocket 以 http 请求开始，原始 TCP 直接发送 auth 包
+        let mut head = [0; 4];
+        stream.peek(&mut head).await?;
+        let (writer, mut read): (Writer, BoxStream<'static, crate::Result<Vec<Packet>>>) =
+            if &head == b"GET " {
+                let websocket = async_tungstenite::tokio::accept_async(stream).await?;
+                let (write, read) = websocket.split();
+                let decoder = PacketDecoder::new(0);
+                let read = read
+                    .map(move |message| match message? {
+                        WsMessage::Binary(bytes) => Ok(decoder.decode_all(&bytes)?),
+                        _ => Ok(vec![]),
+                    })
+                    .boxed();
+                (Writer::WebSocket(write), read)
+            } else {
+                let (read, write) = stream.into_split();
+                let read = FramedRead::new(read, PacketDecoder::new(0))
+                    .map(|packets| Ok(packets?))
+                    .boxed();
+                (Writer::Tcp(write), read)
+            };
+
+        let (tx, rx) = mpsc::unbounded();
+        let write_loop = Box::pin(async move {
+            let mut writer = writer;
+            let mut rx = rx;
+            while let Some(Outgoing::Frame(bytes)) = rx.next().await {
+                if writer.send(bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let read_loop = Box::pin(async move {
+            if let Err(e) = Self::read_loop(&mut read, tx, state).await {
+                debug!("mock live server failed to read: {}", e);
+            }
+        });
+        // 任何一边结束时整个连接都会被 drop
+        futures::future::select(write_loop, read_loop).await;
+        Ok(())
+    }
+
+    async fn read_loop(
+        read: &mut BoxStream<'static, crate::Result<Vec<Packet>>>,
+        tx: UnboundedSender<Outgoing>,
+        state: Arc<Mutex<State>>,
+    ) -> crate::Result<()> {
+        let reply = |op: KnownOperation, body: &str| {
+            Outgoing::Frame(Packet::new(Operation::Known(op), body, 0).to_bytes())
+        };
+
+        let auth = loop {
+            match read.next().await {
+                Some(packets) => {
+                    if let Some(packet) = packets?.into_iter().next() {
+                        break packet;
+                    }
+                }
+                None => return Ok(()),
+            }
+        };
+        state.lock().unwrap().received.push(auth.clone());
+        if auth.operation != Operation::Known(KnownOperation::Auth) {
+            warn!("mock live server expect auth, got {}", auth.operation);
+            return Ok(());
+        }
+        let body: AuthBody = serde_json::from_str(&auth.body)?;
+        let script = {
+            let mut state = state.lock().unwrap();
+            if state.token.as_ref().is_some_and(|token| *token != body.key) {
+                None
+            } else {
+                state.auths.push(body);
+                Some(state.scripts.pop_front().unwrap_or_default())
+            }
+        };
+        let script = match script {
+            Some(script) => script,
+            None => {
+                let _ = tx.unbounded_send(reply(KnownOperation::AuthReply, r#"{"code":-101}"#));
+                let _ = tx.unbounded_send(Outgoing::Drop);
+                // 等写入端发出回复之后再断开
+                futures::future::pending::<()>().await;
+                return Ok(());
+            }
+        };
+        let _ = tx.unbounded_send(reply(KnownOperation::AuthReply, r#"{"code":0}"#));
+
+        let stalled = Arc::new(AtomicBool::new(false));
+        tokio::spawn(Self::run_script(script, tx.clone(), stalled.clone()));
+
+        while let Some(packets) = read.next().await {
+            for packet in packets? {
+                state.lock().unwrap().received.push(packet.clone());
+                if stalled.load(Ordering::SeqCst) {
+                    continue;
+                }
+                match packet.operation {
+                    Operation::Known(KnownOperation::Heartbeat) => {
+                        let popularity = state.lock().unwrap().popularity;
+                        let mut reply = Packet::new(
+                            Operation::Known(KnownOperation::HeartbeatReply),
+                            popularity.to_string(),
+                            0,
+                        );
+                        reply.popularity = Some(popularity);
+                        let _ = tx.unbounded_send(Outgoing::Frame(reply.to_bytes()));
+                    }
+                    Operation::Known(KnownOperation::ChangeRoom) => {
+                        let _ = tx.unbounded_send(reply(
+                            KnownOperation::ChangeRoomReply,
+                            r#"{"code":0}"#,
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_script(
+        script: LiveScript,
+        tx: UnboundedSender<Outgoing>,
+        stalled: Arc<AtomicBool>,
+    ) {
+        for action in script.actions {
+            let outgoing = match action {
+                Action::Frame(bytes) => Outgoing::Frame(bytes),
+                Action::Wait(duration) => {
+                    tokio::time::sleep(duration).await;
+                    continue;
+                }
+                Action::Drop => Outgoing::Drop,
+                Action::Stall => {
+                    stalled.store(true, Ordering::SeqCst);
+                    return;
+                }
+            };
+            if tx.unbounded_send(outgoing).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for MockLiveServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{
+            Host, LiveConnection, LiveMessage, ReconnectPolicy, ReconnectingConnection, Transport,
+        },
+        testing::{Mock, MockServer},
+    };
+
+    fn danmaku(text: &str) -> Packet {
+        Packet::new(Operation::Known(KnownOperation::SendMsgReply), text, 0)
+    }
+
+    fn is_danmaku(packet: &Packet) -> bool {
+        packet.operation == Operation::Known(KnownOperation::SendMsgReply)
+    }
+
+    #[tokio::test]
+    async fn test_websocket_batches() {
+        let server = MockLiveServer::start().await.unwrap();
+        server.set_popularity(114514);
+        server.push_script(
+            LiveScript::new()
+                .batch(&[danmaku("a"), danmaku("b")], 2)
+                .batch(&[danmaku("c")], 3)
+                .send(&danmaku("d")),
+        );
+        let mut con = LiveConnection::builder()
+            .heartbeat_interval(Duration::from_millis(20))
+            .connect(&server.url(), 1, "token".to_string())
+            .await
+            .unwrap();
+
+        let mut texts = vec![];
+        let mut popularity = None;
+        while texts.len() < 4 || popularity.is_none() {
+            let packet = con.next().await.unwrap().unwrap();
+            if is_danmaku(&packet) {
+                texts.push(packet.body);
+            } else if packet.operation == Operation::Known(KnownOperation::HeartbeatReply) {
+                popularity = packet.popularity;
+            }
+        }
+        assert_eq!(texts, ["a", "b", "c", "d"]);
+        assert_eq!(popularity, Some(114514));
+        assert_eq!(server.auths()[0].key, "token");
+        assert_eq!(server.auths()[0].room_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_auth_rejected() {
+        let server = MockLiveServer::start().await.unwrap();
+        server.expect_token("right");
+        let result = LiveConnection::builder()
+            .connect(&server.tcp_url(), 1, "wrong".to_string())
+            .await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::LiveAuthFailed { code: Some(-101) })
+        ));
+        assert!(server.auths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stall_triggers_watchdog() {
+        let server = MockLiveServer::start().await.unwrap();
+        server.push_script(LiveScript::new().send(&danmaku("a")).stall());
+        let mut con = LiveConnection::builder()
+            .heartbeat_interval(Duration::from_millis(20))
+            .watchdog(Some(2))
+            .connect(&server.tcp_url(), 1, "token".to_string())
+            .await
+            .unwrap();
+        assert_eq!(con.next().await.unwrap().unwrap().body, "a");
+        assert!(matches!(
+            con.next().await,
+            Some(Err(crate::Error::LiveTimeout(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_drop() {
+        let live = MockLiveServer::start().await.unwrap();
+        live.push_script(LiveScript::new().send(&danmaku("first")).drop_connection());
+        live.push_script(LiveScript::new().send(&danmaku("second")));
+        let http = MockServer::with_fixtures().await.unwrap();
+        http.mount(
+            Mock::get(Host::ApiLive, "/xlive/web-room/v1/index/getDanmuInfo")
+                .respond(live.danmu_info("token")),
+        );
+
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let builder = LiveConnection::builder().transport(Transport::Tcp);
+        let mut con = ReconnectingConnection::with_builder(http.client(), 646, policy, builder);
+        let mut events = vec![];
+        while events.len() < 5 {
+            match con.next().await.unwrap().unwrap() {
+                LiveMessage::Packet(packet) if is_danmaku(&packet) => {
+                    assert_eq!(packet.room_id, 21133);
+                    events.push(packet.body);
+                }
+                LiveMessage::Packet(_) => {}
+                LiveMessage::Connected { .. } => events.push("connected".to_string()),
+                LiveMessage::Reconnecting { .. } => events.push("reconnecting".to_string()),
+                LiveMessage::Reconnected { .. } => events.push("reconnected".to_string()),
+            }
+        }
+        assert_eq!(
+            events,
+            [
+                "connected",
+                "first",
+                "reconnecting",
+                "reconnected",
+                "second"
+            ]
+        );
+        assert_eq!(live.connections(), 2);
+        assert!(live.auths().iter().all(|auth| auth.room_id == 21133));
+    }
+}
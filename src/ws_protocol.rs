@@ -17,8 +17,20 @@ pub enum ParseError {
 
     #[error("Encoding error: {0}")]
     Encoding(#[from] std::string::FromUtf8Error),
+
+    #[error("Malformed packet: {0}")]
+    Malformed(String),
+
+    #[error("Failed to parse body as json: {0}")]
+    Json(#[from] serde_json::Error),
 }
 
+pub mod event;
+pub use event::{LiveEvent, PacketStreamExt};
+
+mod decoder;
+pub use decoder::{PacketDecoder, RawFrame};
+
 /// pure magic
 pub mod magic {
 
@@ -27,6 +39,7 @@ pub mod magic {
     /// size | head size | ver |  op | seq_id
     pub const HEADER_SIZE: usize = 4 + 2 + 2 + 4 + 4;
 
+    pub const VER_BROTLI: u16 = 3;
     pub const VER_ZLIB_COMPRESSED: u16 = 2;
     pub const VER_NORMAL: u16 = 1;
 
@@ -86,13 +99,17 @@ impl<'de> Deserialize<'de> for Operation {
     where
         D: serde::de::Deserializer<'de>,
     {
-        match KnownOperation::deserialize(deserializer) {
-            Ok(known) => Ok(Operation::Known(known)),
-            Err(e) => {
-                // TODO: implement
-                Err(e)
-            }
+        use serde::de::{Error, IntoDeserializer};
+
+        let s = String::deserialize(deserializer)?;
+        if let Some(op) = s.strip_prefix("Unknown(").and_then(|s| s.strip_suffix(')')) {
+            return op
+                .parse()
+                .map(Operation::Unknown)
+                .map_err(|_| D::Error::custom(format!("invalid operation {}", s)));
         }
+        let de: serde::de::value::StrDeserializer<D::Error> = s.as_str().into_deserializer();
+        KnownOperation::deserialize(de).map(Operation::Known)
     }
 }
 impl From<Operation> for u32 {
@@ -123,135 +140,118 @@ pub struct Packet {
     pub time: DateTime<Local>,
     /// 返回的包会带一个 room_id，表示收到的房间，方便重放
     pub room_id: u64,
+    /// 帧头中的协议版本，对于压缩帧中的 packet 是解压后内层帧的版本
+    #[serde(default)]
+    pub ver: u16,
+    /// 帧头中的 seq_id
+    #[serde(default)]
+    pub seq_id: u32,
+    /// 心跳回复中的人气值，此时 body 为它的字符串形式
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub popularity: Option<u32>,
+    /// 未解码的原始帧（压缩帧为解压前的数据），需要开启 [`PacketDecoder::keep_raw`]
+    #[serde(skip)]
+    pub raw: Option<bytes::Bytes>,
 }
 
-impl Packet {
-    /// 生成一个 auth 包
-    pub fn auth(room_id: u64, token: &str) -> Self {
-        let payload = serde_json::json!({
-            "uid": 0,
-            "roomid": room_id,
-            "protover": 2,
-            "platform": "web",
-            "clientver": "1.14.3",
-            "type": 2,
-            "key": token
-        });
-        let body = serde_json::to_string(&payload).unwrap();
+/// auth 包的内容
+///
+/// uid 为 0 时是匿名连接，收到的弹幕中用户名会被打码
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuthBody {
+    pub uid: u64,
+    #[serde(rename = "roomid")]
+    pub room_id: u64,
+    /// 请求的协议版本，见 [`magic`]
+    pub protover: u16,
+    /// 对应 cookie 中的 buvid3
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub buvid: Option<String>,
+    pub platform: String,
+    pub clientver: String,
+    #[serde(rename = "type")]
+    pub auth_type: u32,
+    /// 从 [`DanmuInfo`][`crate::requests::DanmuInfo`] 拿到的 token
+    pub key: String,
+}
+impl AuthBody {
+    /// 匿名的网页端 auth
+    pub fn new(room_id: u64, token: &str) -> Self {
+        Self {
+            uid: 0,
+            room_id,
+            protover: magic::VER_ZLIB_COMPRESSED,
+            buvid: None,
+            platform: "web".to_string(),
+            clientver: "1.14.3".to_string(),
+            auth_type: 2,
+            key: token.to_string(),
+        }
+    }
+}
 
+impl Packet {
+    /// 生成一个 packet，时间戳为当前时间
+    pub fn new(operation: Operation, body: impl Into<String>, room_id: u64) -> Self {
         Self {
-            operation: Operation::Known(magic::KnownOperation::Auth),
-            body,
+            operation,
+            body: body.into(),
             time: Local::now(),
             room_id,
+            ver: magic::VER_NORMAL,
+            seq_id: 1,
+            popularity: None,
+            raw: None,
         }
     }
+
+    /// 生成一个 auth 包，请求 zlib 压缩（protover 2）
+    pub fn auth(room_id: u64, token: &str) -> Self {
+        Self::auth_with_protover(room_id, token, magic::VER_ZLIB_COMPRESSED)
+    }
+    /// 生成一个 auth 包，指定请求的协议版本
+    ///
+    /// - [`VER_ZLIB_COMPRESSED`][`magic::VER_ZLIB_COMPRESSED`]：使用 zlib 压缩
+    /// - [`VER_BROTLI`][`magic::VER_BROTLI`]：使用 brotli 压缩，网页端目前使用的版本
+    pub fn auth_with_protover(room_id: u64, token: &str, protover: u16) -> Self {
+        Self::auth_with(&AuthBody {
+            protover,
+            ..AuthBody::new(room_id, token)
+        })
+    }
+    /// 从 [`AuthBody`] 生成一个 auth 包
+    pub fn auth_with(auth: &AuthBody) -> Self {
+        let body = serde_json::to_string(auth).unwrap();
+        Self::new(
+            Operation::Known(magic::KnownOperation::Auth),
+            body,
+            auth.room_id,
+        )
+    }
     /// 生成一个心跳包
     pub fn heartbeat() -> Packet {
-        Packet {
-            operation: Operation::Known(magic::KnownOperation::Heartbeat),
-            body: "{}".to_string(),
-            time: Local::now(),
-            room_id: 0,
-        }
+        Packet::new(Operation::Known(magic::KnownOperation::Heartbeat), "{}", 0)
     }
 
-    /// 从 bytes 解析出一堆 [`Packet`]
+    /// 从 bytes 解析出一堆 [`Packet`]，bytes 应该包含若干个完整的帧
+    ///
+    /// 使用默认限制的 [`PacketDecoder`]，需要增量解析时直接使用 [`PacketDecoder`]
     pub fn from_bytes(bytes: &[u8], room_id: u64) -> Result<Vec<Packet>, ParseError> {
-        use byteorder::{BigEndian, ReadBytesExt};
-        use std::io::Read;
-
-        let mut messages = vec![];
-        // parse bytes to messages
-        let mut buffer: &[u8] = bytes;
-        while !buffer.is_empty() {
-            // 见 magic::HEADER_SIZE
-            trace!("parsing header, buffer size = {:?} bytes", buffer.len());
-            if buffer.len() < magic::HEADER_SIZE {
-                debug!("header too small, ignore: {:2x?}", buffer);
-                break;
-            }
-            let total_size = buffer.read_u32::<BigEndian>()?;
-            let _raw_header_size = buffer.read_u16::<BigEndian>()?;
-            let ver = buffer.read_u16::<BigEndian>()?;
-            let operation = buffer.read_u32::<BigEndian>()?;
-            let operation = Operation::from(operation);
-            let seq_id = buffer.read_u32::<BigEndian>()?;
-            trace!("header parsed, seq_id = {}", seq_id);
-            // read rest data
-            let offset = total_size as usize - magic::HEADER_SIZE;
-
-            let body_buffer = &buffer[..offset];
-
-            match (operation, ver) {
-                (_, magic::VER_ZLIB_COMPRESSED) => {
-                    trace!(
-                        "ver = VER_ZLIB_COMPRESSED, op = {:?}, trying decompress",
-                        operation
-                    );
-                    let mut z = flate2::read::ZlibDecoder::new(body_buffer);
-                    let mut buffer = vec![];
-                    let bytes_read = z.read_to_end(&mut buffer)?;
-                    trace!("read {} bytes from zlib", bytes_read);
-                    // 居然还要递归
-                    let sub_messages = Self::from_bytes(&buffer, room_id).map_err(|e| match e {
-                        ParseError::Encoding(e) => {
-                            debug!("utf8 decoded error, raw bytes = {:?}", bytes);
-                            e.into()
-                        }
-                        e => e,
-                    })?;
-                    messages.extend(sub_messages);
-                }
-                (Operation::Known(magic::KnownOperation::HeartbeatReply), magic::VER_NORMAL) => {
-                    // 烦不烦，能不能统一返回 string
-                    let mut body_buffer = body_buffer;
-                    let popularity = body_buffer.read_u32::<BigEndian>()?;
-                    debug!("got a heartbeat response: {}", popularity);
-                    let message = Packet {
-                        operation,
-                        body: popularity.to_string(),
-                        time: Local::now(),
-                        room_id,
-                    };
-                    messages.push(message);
-                }
-                (operation, ver) => {
-                    let body = match String::from_utf8(body_buffer.to_vec()) {
-                        Ok(body) => body,
-                        Err(e) => {
-                            debug!("utf8 decoded error, raw bytes = {:?}", bytes);
-                            warn!(
-                                "Failed to parse body as utf8, op = {:?}, ver = {:?}",
-                                operation, ver
-                            );
-                            return Err(e.into());
-                        }
-                    };
-
-                    let message = Packet {
-                        operation,
-                        body,
-                        time: Local::now(),
-                        room_id,
-                    };
-                    messages.push(message);
-                }
-            }
-
-            buffer = &buffer[offset..];
-        }
-        Ok(messages)
+        PacketDecoder::new(room_id).decode_all(bytes)
     }
 
     /// 从 [`WsMessage`] 解析出一堆 [`Packet`]
     pub fn from_ws_message(ws_message: WsMessage, room_id: u64) -> Result<Vec<Packet>, ParseError> {
         match ws_message {
             WsMessage::Binary(bytes) => Self::from_bytes(&bytes, room_id),
-            WsMessage::Ping(_) => {
+            WsMessage::Ping(_) | WsMessage::Pong(_) => {
                 debug!("received a ping message, ignore");
                 Ok(vec![])
             }
+            WsMessage::Close(frame) => {
+                debug!("received a close message: {:?}", frame);
+                Ok(vec![])
+            }
             ws_message => {
                 warn!("Unknown type of websocket message: {:?}", ws_message);
                 Err(ParseError::WsTypeNotSupported(ws_message.to_string()))
@@ -262,26 +262,81 @@ impl Packet {
 
 impl From<Packet> for WsMessage {
     fn from(msg: Packet) -> WsMessage {
-        use byteorder::{BigEndian, WriteBytesExt};
-
-        let body_size = msg.body.len();
-        let total_size = magic::HEADER_SIZE + body_size;
+        WsMessage::Binary(msg.to_bytes())
+    }
+}
 
-        let mut buffer = vec![0; magic::HEADER_SIZE];
-        buffer.extend_from_slice(msg.body.as_bytes());
+impl Packet {
+    /// 编码为二进制帧，websocket 和 TCP 使用相同的格式
+    ///
+    /// 帧头使用 packet 的 `ver` 和 `seq_id`；`ver` 为
+    /// [`VER_ZLIB_COMPRESSED`][`magic::VER_ZLIB_COMPRESSED`] 或 [`VER_BROTLI`][`magic::VER_BROTLI`]
+    /// 时会先把 packet 编码为普通帧再压缩
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.ver {
+            magic::VER_ZLIB_COMPRESSED | magic::VER_BROTLI => {
+                let inner = Packet {
+                    ver: magic::VER_NORMAL,
+                    ..self.clone()
+                };
+                Self::compress(std::slice::from_ref(&inner), self.ver)
+            }
+            _ => self.raw_frame().to_bytes(),
+        }
+    }
 
-        let mut cursor = std::io::Cursor::new(buffer);
+    /// 把若干个 packet 压缩到同一个帧中，ver 为
+    /// [`VER_ZLIB_COMPRESSED`][`magic::VER_ZLIB_COMPRESSED`] 或 [`VER_BROTLI`][`magic::VER_BROTLI`]，
+    /// 其他值不压缩，直接拼接。
+    ///
+    /// 只有一个 packet 时外层帧使用它的 operation，否则为 `SendMsgReply`
+    pub fn compress(packets: &[Packet], ver: u16) -> Vec<u8> {
+        use std::io::Write;
 
-        cursor.write_u32::<BigEndian>(total_size as u32).unwrap();
-        cursor
-            .write_u16::<BigEndian>(magic::HEADER_SIZE as u16)
-            .unwrap();
-        cursor.write_u16::<BigEndian>(1u16).unwrap();
-        cursor.write_u32::<BigEndian>(msg.operation.into()).unwrap();
-        cursor.write_u32::<BigEndian>(1u32).unwrap();
+        let inner: Vec<u8> = packets.iter().flat_map(|p| p.to_bytes()).collect();
+        let body = match ver {
+            magic::VER_ZLIB_COMPRESSED => {
+                let mut w = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+                w.write_all(&inner).unwrap();
+                w.finish().unwrap()
+            }
+            magic::VER_BROTLI => {
+                let mut w = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+                w.write_all(&inner).unwrap();
+                w.into_inner()
+            }
+            _ => return inner,
+        };
+        let seq_id = packets.first().map(|p| p.seq_id).unwrap_or(0);
+        let operation = match packets {
+            [packet] => packet.operation,
+            _ => Operation::Known(KnownOperation::SendMsgReply),
+        };
+        RawFrame {
+            ver,
+            operation,
+            seq_id,
+            header_len: magic::HEADER_SIZE as u16,
+            body: body.into(),
+        }
+        .to_bytes()
+    }
 
-        let bytes = cursor.into_inner();
-        WsMessage::Binary(bytes)
+    /// 未压缩的帧，心跳回复的人气值编码为 u32
+    fn raw_frame(&self) -> RawFrame {
+        let body = match (self.operation, self.popularity) {
+            (Operation::Known(KnownOperation::HeartbeatReply), Some(popularity)) => {
+                popularity.to_be_bytes().to_vec()
+            }
+            _ => self.body.as_bytes().to_vec(),
+        };
+        RawFrame {
+            ver: self.ver,
+            operation: self.operation,
+            seq_id: self.seq_id,
+            header_len: magic::HEADER_SIZE as u16,
+            body: body.into(),
+        }
     }
 }
 
@@ -322,8 +377,141 @@ mod tests {
         );
     }
 
+    /// 用给定的 ver 把 packets 打包成一个压缩包
+    fn compressed_frame(packets: Vec<Packet>, ver: u16) -> Vec<u8> {
+        use byteorder::{BigEndian, WriteBytesExt};
+        use std::io::Write;
+
+        let mut raw = vec![];
+        for packet in packets {
+            match WsMessage::from(packet) {
+                WsMessage::Binary(bytes) => raw.extend(bytes),
+                _ => unreachable!(),
+            }
+        }
+        let body = match ver {
+            magic::VER_BROTLI => {
+                let mut body = vec![];
+                let mut w = brotli::CompressorWriter::new(&mut body, 4096, 5, 22);
+                w.write_all(&raw).unwrap();
+                drop(w);
+                body
+            }
+            _ => {
+                let mut w = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+                w.write_all(&raw).unwrap();
+                w.finish().unwrap()
+            }
+        };
+        let mut frame = vec![];
+        frame
+            .write_u32::<BigEndian>((magic::HEADER_SIZE + body.len()) as u32)
+            .unwrap();
+        frame
+            .write_u16::<BigEndian>(magic::HEADER_SIZE as u16)
+            .unwrap();
+        frame.write_u16::<BigEndian>(ver).unwrap();
+        frame
+            .write_u32::<BigEndian>(KnownOperation::SendMsgReply as u32)
+            .unwrap();
+        frame.write_u32::<BigEndian>(0).unwrap();
+        frame.extend(body);
+        frame
+    }
+
+    fn send_msg_reply(body: &str) -> Packet {
+        Packet::new(Operation::Known(KnownOperation::SendMsgReply), body, 1)
+    }
+
+    #[test]
+    fn test_from_bytes_compressed() {
+        for ver in [magic::VER_ZLIB_COMPRESSED, magic::VER_BROTLI] {
+            let frame = compressed_frame(
+                vec![
+                    send_msg_reply(r#"{"cmd":"A"}"#),
+                    send_msg_reply(r#"{"cmd":"B"}"#),
+                ],
+                ver,
+            );
+            let packets = Packet::from_bytes(&frame, 5440).unwrap();
+            assert_eq!(packets.len(), 2);
+            assert_eq!(packets[0].body, r#"{"cmd":"A"}"#);
+            assert_eq!(packets[1].body, r#"{"cmd":"B"}"#);
+            assert_eq!(packets[1].room_id, 5440);
+        }
+    }
+
+    #[test]
+    fn test_to_bytes_roundtrip() {
+        for ver in [
+            magic::VER_NORMAL,
+            magic::VER_ZLIB_COMPRESSED,
+            magic::VER_BROTLI,
+        ] {
+            let packet = Packet {
+                ver,
+                seq_id: 7,
+                ..send_msg_reply(r#"{"cmd":"A"}"#)
+            };
+            let packets = Packet::from_bytes(&packet.to_bytes(), 1).unwrap();
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].body, packet.body);
+            assert_eq!(packets[0].seq_id, 7);
+        }
+
+        let batch = Packet::compress(
+            &[
+                send_msg_reply(r#"{"cmd":"A"}"#),
+                send_msg_reply(r#"{"cmd":"B"}"#),
+            ],
+            magic::VER_BROTLI,
+        );
+        assert_eq!(Packet::from_bytes(&batch, 1).unwrap().len(), 2);
+
+        let reply = Packet {
+            popularity: Some(1234),
+            ..Packet::new(Operation::Known(KnownOperation::HeartbeatReply), "", 1)
+        };
+        let packets = Packet::from_bytes(&reply.to_bytes(), 1).unwrap();
+        assert_eq!(packets[0].popularity, Some(1234));
+    }
+
+    #[test]
+    fn test_compressed_operation() {
+        for ver in [magic::VER_ZLIB_COMPRESSED, magic::VER_BROTLI] {
+            for packet in [
+                Packet::heartbeat(),
+                Packet::auth(5440, "token"),
+                Packet::new(
+                    Operation::Known(KnownOperation::ChangeRoom),
+                    r#"{"room_id":21133}"#,
+                    5440,
+                ),
+            ] {
+                let compressed = Packet { ver, ..packet };
+                let bytes = compressed.to_bytes();
+                // 外层帧的 operation
+                assert_eq!(
+                    Operation::from(u32::from_be_bytes(bytes[8..12].try_into().unwrap())),
+                    compressed.operation
+                );
+                let packets = Packet::from_bytes(&bytes, 5440).unwrap();
+                assert_eq!(packets.len(), 1);
+                assert_eq!(packets[0].operation, compressed.operation);
+                assert_eq!(packets[0].body, compressed.body);
+            }
+        }
+    }
+
+    #[test]
+    fn test_auth_protover() {
+        let auth = Packet::auth_with_protover(5440, "token", magic::VER_BROTLI);
+        let body: serde_json::Value = serde_json::from_str(&auth.body).unwrap();
+        assert_eq!(body["protover"], 3);
+        assert_eq!(body["roomid"], 5440);
+    }
+
     #[test]
-    #[ignore = "not yet implemented"]
     fn test_operation_deserialize_unknown() {
         assert_eq!(
             serde_json::from_str::<Test>(r#"{"op":"Unknown(114514)"}"#).unwrap(),
@@ -0,0 +1,472 @@
+//! 增量的帧解析器
+//!
+//! 帧头里的长度都是不可信的，所有的长度在使用前都要检查
+
+use std::io::Read;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use bytes::{Bytes, BytesMut};
+
+use super::{magic, KnownOperation, Operation, Packet, ParseError};
+
+/// 未解码的原始帧，保留了帧头中的所有信息，可以用于协议调试和原样重放
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub ver: u16,
+    pub operation: Operation,
+    pub seq_id: u32,
+    /// 帧头长度，一般为 [`HEADER_SIZE`][`magic::HEADER_SIZE`]
+    pub header_len: u16,
+    /// 帧头之后的数据，压缩帧为压缩后的数据
+    pub body: Bytes,
+}
+
+impl RawFrame {
+    /// 从一个完整的帧解析，帧头需要已经由 [`PacketDecoder::frame_len`] 检查过
+    fn from_checked(frame: Bytes) -> Self {
+        let header_len = BigEndian::read_u16(&frame[4..6]);
+        Self {
+            ver: BigEndian::read_u16(&frame[6..8]),
+            operation: Operation::from(BigEndian::read_u32(&frame[8..12])),
+            seq_id: BigEndian::read_u32(&frame[12..16]),
+            header_len,
+            body: frame.slice(header_len as usize..),
+        }
+    }
+
+    /// 编码为二进制帧
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header_len = (self.header_len as usize).max(magic::HEADER_SIZE);
+        let mut bytes = vec![0; header_len];
+        BigEndian::write_u32(&mut bytes[0..4], (header_len + self.body.len()) as u32);
+        BigEndian::write_u16(&mut bytes[4..6], header_len as u16);
+        BigEndian::write_u16(&mut bytes[6..8], self.ver);
+        BigEndian::write_u32(&mut bytes[8..12], self.operation.into());
+        BigEndian::write_u32(&mut bytes[12..16], self.seq_id);
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// 帧解析器，实现了 [`tokio_util::codec::Decoder`]，可以处理跨多次读取的帧
+///
+/// 每个帧解析为若干个 [`Packet`]（压缩帧中可能包含多个 packet）
+///
+/// ```
+/// use biliapi::ws_protocol::{Packet, PacketDecoder};
+/// use tokio_util::codec::Decoder;
+///
+/// let bytes = Packet::heartbeat().to_bytes();
+/// let mut decoder = PacketDecoder::new(5440);
+/// let mut buffer = bytes::BytesMut::from(&bytes[..10]);
+/// // 数据不够一个完整的帧
+/// assert!(decoder.decode(&mut buffer).unwrap().is_none());
+/// buffer.extend_from_slice(&bytes[10..]);
+/// assert_eq!(decoder.decode(&mut buffer).unwrap().unwrap().len(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct PacketDecoder {
+    room_id: u64,
+    max_frame_size: usize,
+    max_decompressed_size: usize,
+    keep_raw: bool,
+}
+
+impl PacketDecoder {
+    /// 解析出的 packet 会带上 room_id
+    pub fn new(room_id: u64) -> Self {
+        Self {
+            room_id,
+            max_frame_size: 4 * 1024 * 1024,
+            max_decompressed_size: 16 * 1024 * 1024,
+            keep_raw: false,
+        }
+    }
+
+    /// 解析出的 packet 所属的房间号
+    pub fn room_id(&self) -> u64 {
+        self.room_id
+    }
+
+    /// 单个帧的最大长度，默认为 4 MiB
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// 压缩帧解压后的最大长度，默认为 16 MiB
+    pub fn max_decompressed_size(mut self, size: usize) -> Self {
+        self.max_decompressed_size = size;
+        self
+    }
+
+    /// 在 [`Packet::raw`] 中保留解码前的帧，压缩帧中的 packet 会共享同一个压缩帧
+    pub fn keep_raw(mut self, keep_raw: bool) -> Self {
+        self.keep_raw = keep_raw;
+        self
+    }
+
+    /// 解析包含若干个完整帧的数据，如一个 websocket 消息
+    pub fn decode_all(&self, bytes: &[u8]) -> Result<Vec<Packet>, ParseError> {
+        let mut packets = vec![];
+        for frame in self.split_frames(bytes)? {
+            self.decode_into(frame, false, &mut packets)?;
+        }
+        Ok(packets)
+    }
+
+    /// 把包含若干个完整帧的数据拆分为 [`RawFrame`]，不进行解压
+    pub fn split_raw_frames(&self, bytes: &[u8]) -> Result<Vec<RawFrame>, ParseError> {
+        Ok(self
+            .split_frames(bytes)?
+            .into_iter()
+            .map(RawFrame::from_checked)
+            .collect())
+    }
+
+    /// 解码一个 [`RawFrame`]
+    pub fn decode_frame(&self, frame: &RawFrame) -> Result<Vec<Packet>, ParseError> {
+        let mut packets = vec![];
+        self.decode_raw(frame, false, &mut packets)?;
+        Ok(packets)
+    }
+
+    /// 检查帧头，返回完整帧的长度；数据不够时返回 None
+    fn frame_len(&self, buffer: &[u8]) -> Result<Option<usize>, ParseError> {
+        if buffer.len() < magic::HEADER_SIZE {
+            return Ok(None);
+        }
+        let total_size = BigEndian::read_u32(&buffer[0..4]) as usize;
+        let header_size = BigEndian::read_u16(&buffer[4..6]) as usize;
+        if header_size < magic::HEADER_SIZE {
+            return Err(ParseError::Malformed(format!(
+                "header size {} is smaller than {}",
+                header_size,
+                magic::HEADER_SIZE
+            )));
+        }
+        if total_size < header_size {
+            return Err(ParseError::Malformed(format!(
+                "packet size {} is smaller than header size {}",
+                total_size, header_size
+            )));
+        }
+        if total_size > self.max_frame_size {
+            return Err(ParseError::Malformed(format!(
+                "packet size {} exceeds limit {}",
+                total_size, self.max_frame_size
+            )));
+        }
+        if buffer.len() < total_size {
+            return Ok(None);
+        }
+        Ok(Some(total_size))
+    }
+
+    /// 拆分出所有完整的帧
+    fn split_frames(&self, bytes: &[u8]) -> Result<Vec<Bytes>, ParseError> {
+        let mut frames = vec![];
+        let mut buffer = bytes;
+        while !buffer.is_empty() {
+            trace!("parsing header, buffer size = {:?} bytes", buffer.len());
+            match self.frame_len(buffer)? {
+                Some(len) => {
+                    frames.push(Bytes::copy_from_slice(&buffer[..len]));
+                    buffer = &buffer[len..];
+                }
+                None if buffer.len() < magic::HEADER_SIZE => {
+                    debug!("header too small, ignore: {:2x?}", buffer);
+                    break;
+                }
+                None => {
+                    return Err(ParseError::Malformed(format!(
+                        "truncated packet, only {} bytes left",
+                        buffer.len()
+                    )));
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// 解码一个完整的帧，并按需保留原始数据
+    fn decode_into(
+        &self,
+        frame: Bytes,
+        decompressed: bool,
+        packets: &mut Vec<Packet>,
+    ) -> Result<(), ParseError> {
+        let start = packets.len();
+        self.decode_raw(
+            &RawFrame::from_checked(frame.clone()),
+            decompressed,
+            packets,
+        )?;
+        if self.keep_raw {
+            for packet in &mut packets[start..] {
+                packet.raw = Some(frame.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_raw(
+        &self,
+        frame: &RawFrame,
+        decompressed: bool,
+        packets: &mut Vec<Packet>,
+    ) -> Result<(), ParseError> {
+        let RawFrame {
+            ver,
+            operation,
+            seq_id,
+            ..
+        } = *frame;
+        trace!("header parsed, seq_id = {}", seq_id);
+        let body_buffer = &frame.body[..];
+
+        match (operation, ver) {
+            (_, magic::VER_ZLIB_COMPRESSED | magic::VER_BROTLI) => {
+                // 正常情况下压缩帧里面不会再有压缩帧，防止被递归解压
+                if decompressed {
+                    return Err(ParseError::Malformed(
+                        "nested compressed packet".to_string(),
+                    ));
+                }
+                trace!("ver = {}, op = {:?}, trying decompress", ver, operation);
+                let limit = self.max_decompressed_size as u64 + 1;
+                let mut buffer = vec![];
+                let bytes_read = if ver == magic::VER_BROTLI {
+                    brotli::Decompressor::new(body_buffer, 4096)
+                        .take(limit)
+                        .read_to_end(&mut buffer)?
+                } else {
+                    flate2::read::ZlibDecoder::new(body_buffer)
+                        .take(limit)
+                        .read_to_end(&mut buffer)?
+                };
+                trace!("read {} bytes from compressed body", bytes_read);
+                if bytes_read > self.max_decompressed_size {
+                    return Err(ParseError::Malformed(format!(
+                        "decompressed size exceeds limit {}",
+                        self.max_decompressed_size
+                    )));
+                }
+                // 居然还要递归
+                let frames = self
+                    .split_frames(&buffer)
+                    .inspect_err(|_| debug!("decompressed bytes = {:?}", buffer))?;
+                for frame in frames {
+                    self.decode_into(frame, true, packets)?;
+                }
+            }
+            (Operation::Known(KnownOperation::HeartbeatReply), magic::VER_NORMAL) => {
+                // 烦不烦，能不能统一返回 string
+                let mut body_buffer = body_buffer;
+                let popularity = body_buffer.read_u32::<BigEndian>()?;
+                debug!("got a heartbeat response: {}", popularity);
+                packets.push(Packet {
+                    popularity: Some(popularity),
+                    ..self.packet(frame, popularity.to_string())
+                });
+            }
+            (operation, ver) => {
+                let body = match String::from_utf8(body_buffer.to_vec()) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        debug!("utf8 decoded error, raw bytes = {:?}", frame.body);
+                        warn!(
+                            "Failed to parse body as utf8, op = {:?}, ver = {:?}",
+                            operation, ver
+                        );
+                        return Err(e.into());
+                    }
+                };
+                packets.push(self.packet(frame, body));
+            }
+        }
+        Ok(())
+    }
+
+    fn packet(&self, frame: &RawFrame, body: String) -> Packet {
+        Packet {
+            ver: frame.ver,
+            seq_id: frame.seq_id,
+            ..Packet::new(frame.operation, body, self.room_id)
+        }
+    }
+}
+
+impl tokio_util::codec::Decoder for PacketDecoder {
+    type Item = Vec<Packet>;
+    type Error = ParseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.frame_len(src)? {
+            Some(len) => {
+                let frame = src.split_to(len).freeze();
+                let mut packets = vec![];
+                self.decode_into(frame, false, &mut packets)?;
+                Ok(Some(packets))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use tokio_util::codec::Decoder;
+
+    fn frame(header_size: u16, ver: u16, op: u32, body: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        frame
+            .write_u32::<BigEndian>(header_size as u32 + body.len() as u32)
+            .unwrap();
+        frame.write_u16::<BigEndian>(header_size).unwrap();
+        frame.write_u16::<BigEndian>(ver).unwrap();
+        frame.write_u32::<BigEndian>(op).unwrap();
+        frame.write_u32::<BigEndian>(1).unwrap();
+        frame.resize(header_size as usize, 0);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    fn test_header_size_honoured() {
+        let bytes = frame(20, 0, 5, br#"{"cmd":"A"}"#);
+        let packets = Packet::from_bytes(&bytes, 1).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].body, r#"{"cmd":"A"}"#);
+    }
+
+    #[test]
+    fn test_malformed_frames() {
+        // 截断的帧
+        let bytes = frame(16, 0, 5, br#"{"cmd":"A"}"#);
+        assert!(Packet::from_bytes(&bytes[..20], 1).is_err());
+        // total size 比 header 小
+        let mut bytes = frame(16, 0, 5, b"");
+        bytes[3] = 4;
+        assert!(Packet::from_bytes(&bytes, 1).is_err());
+        // header size 比 16 小
+        let mut bytes = frame(16, 0, 5, b"abc");
+        bytes[5] = 2;
+        assert!(Packet::from_bytes(&bytes, 1).is_err());
+        // 心跳回复的 body 不够 4 字节
+        let bytes = frame(16, magic::VER_NORMAL, 3, b"ab");
+        assert!(Packet::from_bytes(&bytes, 1).is_err());
+        // 无法解压的压缩帧
+        let bytes = frame(16, magic::VER_ZLIB_COMPRESSED, 5, b"not zlib");
+        assert!(Packet::from_bytes(&bytes, 1).is_err());
+    }
+
+    #[test]
+    fn test_frame_size_limits() {
+        use std::io::Write;
+
+        let bytes = frame(16, 0, 5, &[b'a'; 100]);
+        assert!(PacketDecoder::new(1)
+            .max_frame_size(64)
+            .decode_all(&bytes)
+            .is_err());
+
+        let inner = frame(16, 0, 5, &[b'a'; 1000]);
+        let mut w = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        w.write_all(&inner).unwrap();
+        let bytes = frame(16, magic::VER_ZLIB_COMPRESSED, 5, &w.finish().unwrap());
+        assert!(PacketDecoder::new(1)
+            .max_decompressed_size(100)
+            .decode_all(&bytes)
+            .is_err());
+        assert_eq!(PacketDecoder::new(1).decode_all(&bytes).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_incremental_decode() {
+        let mut bytes = frame(16, 0, 5, br#"{"cmd":"A"}"#);
+        bytes.extend(frame(16, 0, 5, br#"{"cmd":"B"}"#));
+
+        let mut decoder = PacketDecoder::new(1);
+        let mut buffer = BytesMut::new();
+        let mut bodies = vec![];
+        // 每次只喂一个字节
+        for b in bytes {
+            buffer.extend_from_slice(&[b]);
+            while let Some(packets) = decoder.decode(&mut buffer).unwrap() {
+                bodies.extend(packets.into_iter().map(|p| p.body));
+            }
+        }
+        assert_eq!(bodies, vec![r#"{"cmd":"A"}"#, r#"{"cmd":"B"}"#]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_header_metadata() {
+        let bytes = frame(16, magic::VER_NORMAL, 3, &[0, 0, 1, 0]);
+        let packets = PacketDecoder::new(1)
+            .keep_raw(true)
+            .decode_all(&bytes)
+            .unwrap();
+        assert_eq!(packets[0].popularity, Some(256));
+        assert_eq!(packets[0].body, "256");
+        assert_eq!(packets[0].ver, magic::VER_NORMAL);
+        assert_eq!(packets[0].seq_id, 1);
+        assert_eq!(packets[0].raw.as_deref(), Some(&bytes[..]));
+
+        let frames = PacketDecoder::new(1).split_raw_frames(&bytes).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            frames[0].operation,
+            Operation::Known(KnownOperation::HeartbeatReply)
+        );
+        assert_eq!(frames[0].header_len, 16);
+        assert_eq!(&frames[0].body[..], &[0, 0, 1, 0]);
+        assert_eq!(frames[0].to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_keep_compressed_raw() {
+        use std::io::Write;
+
+        let mut inner = frame(16, 0, 5, br#"{"cmd":"A"}"#);
+        inner.extend(frame(16, 0, 5, br#"{"cmd":"B"}"#));
+        let mut w = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        w.write_all(&inner).unwrap();
+        let bytes = frame(16, magic::VER_ZLIB_COMPRESSED, 5, &w.finish().unwrap());
+
+        let packets = PacketDecoder::new(1)
+            .keep_raw(true)
+            .decode_all(&bytes)
+            .unwrap();
+        assert_eq!(packets.len(), 2);
+        for packet in packets {
+            assert_eq!(packet.ver, 0);
+            assert_eq!(packet.raw.as_deref(), Some(&bytes[..]));
+        }
+        assert!(Packet::from_bytes(&bytes, 1).unwrap()[0].raw.is_none());
+    }
+
+    #[test]
+    fn test_garbage_never_panics() {
+        // 简单的线性同余随机数
+        let mut seed: u32 = 114514;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        let valid = frame(16, 0, 5, br#"{"cmd":"A"}"#);
+        for _ in 0..2000 {
+            let mut bytes = valid.clone();
+            for _ in 0..4 {
+                let idx = next() as usize % bytes.len();
+                bytes[idx] = next();
+            }
+            let len = next() as usize % (bytes.len() + 1);
+            let _ = Packet::from_bytes(&bytes[..len], 1);
+            let _ = PacketDecoder::new(1).decode(&mut BytesMut::from(&bytes[..len]));
+        }
+    }
+}
@@ -0,0 +1,617 @@
+//! 把 [`Packet`] 的 body 解析成强类型的直播间事件
+//!
+//! b 站推送的消息都是 `{"cmd": "...", ...}` 形式的 json，这里按 `cmd` 分发到不同的结构
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
+
+use super::{KnownOperation, Operation, Packet, ParseError};
+
+/// 直播间事件，由 [`Packet::decode_event`] 解析得到
+#[derive(Debug, Clone, Serialize)]
+pub enum LiveEvent {
+    /// DANMU_MSG，弹幕
+    Danmaku(Danmaku),
+    /// SEND_GIFT，送礼物
+    Gift(Gift),
+    /// SUPER_CHAT_MESSAGE，醒目留言
+    SuperChat(SuperChat),
+    /// GUARD_BUY，上舰
+    GuardBuy(GuardBuy),
+    /// INTERACT_WORD，进入直播间、关注、分享等互动
+    Interact(Interact),
+    /// ENTRY_EFFECT，舰长等进入直播间的特效
+    EntryEffect(EntryEffect),
+    /// LIVE，开播
+    Live { room_id: u64 },
+    /// PREPARING，下播
+    Preparing { room_id: u64 },
+    /// ROOM_CHANGE，直播间标题、分区修改
+    RoomChange(RoomChange),
+    /// WATCHED_CHANGE，看过的人数
+    WatchedChange(WatchedChange),
+    /// ONLINE_RANK_COUNT，高能榜人数
+    OnlineRankCount { count: u64 },
+    /// LIKE_INFO_V3_UPDATE，点赞数
+    LikeCount { count: u64 },
+    /// CUT_OFF，直播被切断
+    CutOff { message: String },
+    /// 未知或者未实现的 cmd，原样保留
+    Unknown { cmd: String, raw: Value },
+}
+
+impl LiveEvent {
+    /// 从 json body 解析出一个事件
+    ///
+    /// cmd 可能会带有后缀，如 `DANMU_MSG:4:0:2:2:2:0`，这里会忽略冒号后面的部分。
+    ///
+    /// 已知的 cmd 如果因为 b 站修改了格式而解析失败，会退化为 [`Unknown`][`LiveEvent::Unknown`]，
+    /// 只有 body 不是 json 时才会返回错误。
+    pub fn from_json(body: &str) -> Result<Self, ParseError> {
+        let raw: Value = serde_json::from_str(body)?;
+        let cmd = raw
+            .get("cmd")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let base_cmd = cmd.split(':').next().unwrap_or_default();
+
+        match Self::decode(base_cmd, &raw) {
+            Ok(Some(event)) => Ok(event),
+            Ok(None) => Ok(Self::Unknown { cmd, raw }),
+            Err(e) => {
+                warn!("failed to decode {}, fallback to unknown: {}", cmd, e);
+                Ok(Self::Unknown { cmd, raw })
+            }
+        }
+    }
+
+    fn decode(base_cmd: &str, raw: &Value) -> serde_json::Result<Option<Self>> {
+        let event = match base_cmd {
+            "DANMU_MSG" => Self::Danmaku(Danmaku::deserialize(field(raw, "info"))?),
+            "SEND_GIFT" => Self::Gift(Gift::deserialize(field(raw, "data"))?),
+            "SUPER_CHAT_MESSAGE" => Self::SuperChat(SuperChat::deserialize(field(raw, "data"))?),
+            "GUARD_BUY" => Self::GuardBuy(GuardBuy::deserialize(field(raw, "data"))?),
+            "INTERACT_WORD" => Self::Interact(Interact::deserialize(field(raw, "data"))?),
+            "ENTRY_EFFECT" => Self::EntryEffect(EntryEffect::deserialize(field(raw, "data"))?),
+            "LIVE" => Self::Live {
+                room_id: RoomId::deserialize(raw)?.room_id,
+            },
+            "PREPARING" => Self::Preparing {
+                room_id: RoomId::deserialize(raw)?.room_id,
+            },
+            "ROOM_CHANGE" => Self::RoomChange(RoomChange::deserialize(field(raw, "data"))?),
+            "WATCHED_CHANGE" => {
+                Self::WatchedChange(WatchedChange::deserialize(field(raw, "data"))?)
+            }
+            "ONLINE_RANK_COUNT" => Self::OnlineRankCount {
+                count: Count::deserialize(field(raw, "data"))?.count,
+            },
+            "LIKE_INFO_V3_UPDATE" => Self::LikeCount {
+                count: ClickCount::deserialize(field(raw, "data"))?.click_count,
+            },
+            "CUT_OFF" => Self::CutOff {
+                message: raw
+                    .get("msg")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+
+    /// 事件对应的 cmd 名字，[`Unknown`][`LiveEvent::Unknown`] 会返回原始的 cmd
+    pub fn cmd(&self) -> &str {
+        match self {
+            Self::Danmaku(_) => "DANMU_MSG",
+            Self::Gift(_) => "SEND_GIFT",
+            Self::SuperChat(_) => "SUPER_CHAT_MESSAGE",
+            Self::GuardBuy(_) => "GUARD_BUY",
+            Self::Interact(_) => "INTERACT_WORD",
+            Self::EntryEffect(_) => "ENTRY_EFFECT",
+            Self::Live { .. } => "LIVE",
+            Self::Preparing { .. } => "PREPARING",
+            Self::RoomChange(_) => "ROOM_CHANGE",
+            Self::WatchedChange(_) => "WATCHED_CHANGE",
+            Self::OnlineRankCount { .. } => "ONLINE_RANK_COUNT",
+            Self::LikeCount { .. } => "LIKE_INFO_V3_UPDATE",
+            Self::CutOff { .. } => "CUT_OFF",
+            Self::Unknown { cmd, .. } => cmd,
+        }
+    }
+}
+
+fn field<'a>(raw: &'a Value, name: &str) -> &'a Value {
+    raw.get(name).unwrap_or(&Value::Null)
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct RoomId {
+    #[serde(rename = "roomid")]
+    #[serde_as(as = "PickFirst<(_, DisplayFromStr)>")]
+    room_id: u64,
+}
+
+#[derive(Deserialize)]
+struct Count {
+    count: u64,
+}
+
+#[derive(Deserialize)]
+struct ClickCount {
+    click_count: u64,
+}
+
+/// 一条弹幕，从 DANMU_MSG 的 `info` 数组解析而来
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Danmaku {
+    /// 弹幕内容
+    pub text: String,
+    /// 弹幕模式，1 滚动，4 底部，5 顶部
+    pub mode: u32,
+    /// 字号
+    pub font_size: u32,
+    /// 颜色，如 0xFFFFFF
+    pub color: u32,
+    /// 发送时间
+    pub timestamp: DateTime<Utc>,
+    /// 表情弹幕
+    pub emoticon: Option<Emoticon>,
+    /// 发送者
+    pub sender: DanmakuSender,
+    /// 粉丝勋章，没戴的时候为 None
+    pub medal: Option<FanMedal>,
+    /// 用户直播等级（UL）
+    pub user_level: u32,
+    /// 大航海等级，0 无，1 总督，2 提督，3 舰长
+    pub guard_level: u32,
+}
+
+/// [`Danmaku`] 的发送者
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DanmakuSender {
+    /// 匿名连接时为 0
+    pub uid: u64,
+    /// uid 的 crc32（十六进制），匿名连接时也有值，用于导出弹幕 XML
+    pub hash: String,
+    /// 用户名，匿名连接时会被打码
+    pub name: String,
+    /// 是否房管
+    pub is_admin: bool,
+}
+
+/// 表情弹幕
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Emoticon {
+    #[serde(rename = "emoticon_unique", default)]
+    pub unique: String,
+    pub url: String,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+}
+
+/// 粉丝勋章
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FanMedal {
+    #[serde(rename = "medal_level")]
+    pub level: u32,
+    #[serde(rename = "medal_name")]
+    pub name: String,
+    /// 勋章对应的主播名
+    #[serde(rename = "anchor_uname", default)]
+    pub anchor_name: String,
+    /// 勋章对应的直播间
+    #[serde(rename = "anchor_roomid", default)]
+    pub room_id: u64,
+}
+
+impl<'de> Deserialize<'de> for Danmaku {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        // info 是一个位置数组，各个位置的含义见下面
+        let info = Vec::<Value>::deserialize(deserializer)?;
+        let get = |idx: usize| info.get(idx).unwrap_or(&Value::Null);
+        let meta = get(0);
+        let meta = |idx: usize| meta.get(idx).unwrap_or(&Value::Null);
+        let as_u32 = |v: &Value| v.as_u64().unwrap_or_default() as u32;
+
+        let text = get(1)
+            .as_str()
+            .ok_or_else(|| D::Error::custom("danmaku text not found"))?
+            .to_string();
+
+        // [0, mode, font_size, color, timestamp_ms, rnd, 0, uid_hash, 0, 0, 0, "", dm_type, emoticon, ...]
+        let timestamp_ms = meta(4).as_i64().unwrap_or_default();
+        let timestamp = Utc
+            .timestamp_millis_opt(timestamp_ms)
+            .single()
+            .ok_or_else(|| D::Error::custom("invalid danmaku timestamp"))?;
+        let emoticon = match meta(13) {
+            emoticon @ Value::Object(_) => Emoticon::deserialize(emoticon).ok(),
+            _ => None,
+        };
+
+        // [uid, name, is_admin, vip, svip, rank, mobile_verify, name_color]
+        let user = get(2);
+        let sender = DanmakuSender {
+            uid: user.get(0).and_then(Value::as_u64).unwrap_or_default(),
+            hash: meta(7).as_str().unwrap_or_default().to_string(),
+            name: user
+                .get(1)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            is_admin: user.get(2).and_then(Value::as_u64).unwrap_or_default() == 1,
+        };
+
+        // [level, name, anchor_name, room_id, color, ...]，没有勋章时为空数组
+        let medal = get(3);
+        let medal = match medal.get(0).and_then(Value::as_u64) {
+            Some(level) if level > 0 => Some(FanMedal {
+                level: level as u32,
+                name: medal
+                    .get(1)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                anchor_name: medal
+                    .get(2)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                room_id: medal.get(3).and_then(Value::as_u64).unwrap_or_default(),
+            }),
+            _ => None,
+        };
+
+        // [level, 0, color, rank]
+        let user_level = get(4).get(0).map(as_u32).unwrap_or_default();
+
+        Ok(Self {
+            text,
+            mode: as_u32(meta(1)),
+            font_size: as_u32(meta(2)),
+            color: as_u32(meta(3)),
+            timestamp,
+            emoticon,
+            sender,
+            medal,
+            user_level,
+            guard_level: as_u32(get(7)),
+        })
+    }
+}
+
+/// 勋章等级为 0 表示没有佩戴勋章
+fn deserialize_medal<'de, D>(deserializer: D) -> Result<Option<FanMedal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let medal = Option::<FanMedal>::deserialize(deserializer)?;
+    Ok(medal.filter(|m| m.level > 0))
+}
+
+/// 礼物
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Gift {
+    pub uid: u64,
+    #[serde(rename = "uname")]
+    pub username: String,
+    /// 头像
+    #[serde(rename = "face", default)]
+    pub avatar_url: String,
+    #[serde(rename = "giftId")]
+    pub gift_id: u64,
+    #[serde(rename = "giftName")]
+    pub gift_name: String,
+    /// 礼物数量
+    pub num: u64,
+    /// 单价，gold 时 1000 对应 1 元
+    pub price: u64,
+    /// 总价
+    #[serde(default)]
+    pub total_coin: u64,
+    /// gold（金瓜子）或者 silver（银瓜子）
+    pub coin_type: String,
+    /// 一般为 “投喂”
+    #[serde(default)]
+    pub action: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(rename = "medal_info", default, deserialize_with = "deserialize_medal")]
+    pub medal: Option<FanMedal>,
+}
+
+/// 醒目留言
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SuperChat {
+    pub id: u64,
+    pub uid: u64,
+    /// 价格，单位为元
+    pub price: u64,
+    pub message: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub end_time: DateTime<Utc>,
+    pub user_info: SuperChatUser,
+    #[serde(rename = "medal_info", default, deserialize_with = "deserialize_medal")]
+    pub medal: Option<FanMedal>,
+}
+
+/// [`SuperChat`] 的发送者信息
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SuperChatUser {
+    #[serde(rename = "uname")]
+    pub username: String,
+    #[serde(rename = "face", default)]
+    pub avatar_url: String,
+    #[serde(default)]
+    pub guard_level: u32,
+    #[serde(default)]
+    pub user_level: u32,
+}
+
+/// 上舰
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GuardBuy {
+    pub uid: u64,
+    pub username: String,
+    /// 1 总督，2 提督，3 舰长
+    pub guard_level: u32,
+    /// 数量（月）
+    pub num: u64,
+    /// 单价，单位为金瓜子
+    pub price: u64,
+    pub gift_id: u64,
+    pub gift_name: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub end_time: DateTime<Utc>,
+}
+
+/// 互动，见 [`Interact::msg_type`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Interact {
+    pub uid: u64,
+    #[serde(rename = "uname")]
+    pub username: String,
+    /// 1 进入直播间，2 关注，3 分享，4 特别关注，5 互相关注
+    pub msg_type: u32,
+    #[serde(rename = "roomid")]
+    pub room_id: u64,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(rename = "fans_medal", default, deserialize_with = "deserialize_medal")]
+    pub medal: Option<FanMedal>,
+}
+
+/// 进场特效
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EntryEffect {
+    pub id: u64,
+    pub uid: u64,
+    /// 主播 uid
+    pub target_id: u64,
+    /// 提示文字，如 “欢迎舰长 <%xxx%> 进入直播间”
+    pub copy_writing: String,
+    /// 大航海等级
+    #[serde(default)]
+    pub privilege_type: u32,
+    #[serde(rename = "face", default)]
+    pub avatar_url: String,
+}
+
+/// 直播间信息修改
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoomChange {
+    pub title: String,
+    pub area_id: u64,
+    pub area_name: String,
+    pub parent_area_id: u64,
+    pub parent_area_name: String,
+}
+
+/// 看过的人数
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchedChange {
+    pub num: u64,
+    /// 如 “1.2万人看过”
+    #[serde(default)]
+    pub text_large: String,
+}
+
+impl Packet {
+    /// 只读取 body 中的 cmd，不解析整个事件，用于提前过滤不需要的 packet
+    ///
+    /// 非 [`SendMsgReply`][`KnownOperation::SendMsgReply`] 或者没有 cmd 时返回 None
+    pub fn event_cmd(&self) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Cmd {
+            cmd: String,
+        }
+        match self.operation {
+            Operation::Known(KnownOperation::SendMsgReply) => {
+                serde_json::from_str::<Cmd>(&self.body).ok().map(|c| c.cmd)
+            }
+            _ => None,
+        }
+    }
+
+    /// 把 packet 解析成 [`LiveEvent`]
+    ///
+    /// 只有 [`SendMsgReply`][`KnownOperation::SendMsgReply`] 会携带事件，其他的 packet（如心跳回复）返回 None
+    pub fn decode_event(&self) -> Result<Option<LiveEvent>, ParseError> {
+        match self.operation {
+            Operation::Known(KnownOperation::SendMsgReply) => {
+                LiveEvent::from_json(&self.body).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// 把 `Stream<Item = Result<Packet>>` 转换为 `Stream<Item = Result<LiveEvent>>`，
+/// 见 [`PacketStreamExt::events`]
+pub struct Events<S> {
+    inner: S,
+}
+
+impl<S> Stream for Events<S>
+where
+    S: Stream<Item = crate::Result<Packet>> + Unpin,
+{
+    type Item = crate::Result<LiveEvent>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let packet = match futures::ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(packet)) => packet,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+            match packet.decode_event() {
+                Ok(Some(event)) => return Poll::Ready(Some(Ok(event))),
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+        }
+    }
+}
+
+/// 为所有产生 [`Packet`] 的 stream（如 [`LiveConnection`][`crate::connection::LiveConnection`]）提供事件解析
+///
+/// ```no_run
+/// # use biliapi::connection::LiveConnection;
+/// # let (url, room_id, token) = ("", 1, "".to_string());
+/// # tokio_test::block_on(async {
+/// use biliapi::ws_protocol::{LiveEvent, PacketStreamExt};
+/// use futures::StreamExt;
+///
+/// let con = LiveConnection::new(url, room_id, token).await.unwrap();
+/// let mut events = con.events();
+/// while let Some(event) = events.next().await {
+///     if let LiveEvent::Danmaku(danmaku) = event.unwrap() {
+///         println!("{}: {}", danmaku.sender.name, danmaku.text);
+///     }
+/// }
+/// # });
+/// ```
+pub trait PacketStreamExt: Stream<Item = crate::Result<Packet>> + Sized {
+    /// 只保留可以解析为事件的 packet
+    fn events(self) -> Events<Self> {
+        Events { inner: self }
+    }
+}
+impl<S> PacketStreamExt for S where S: Stream<Item = crate::Result<Packet>> + Sized {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_danmaku() {
+        let body = r#"{"cmd":"DANMU_MSG:4:0:2:2:2:0","info":[[0,1,25,16777215,1650000000123,1650000000,0,"8a3f1e2c",0,0,0,"",0,"{}","{}",{"mode":0}],"草",[123456,"某用户",1,0,0,10000,1,""],[21,"超果",  "超果果mc",21133,398668,"",0,6809855,398668,6850801,3,1,13046],[25,0,5805790,">50000",0],["",""],0,3,null,{"ts":1650000000,"ct":"ABCD"},0,0,null,null,0,105]}"#;
+        let event = LiveEvent::from_json(body).unwrap();
+        let danmaku = match event {
+            LiveEvent::Danmaku(d) => d,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(danmaku.text, "草");
+        assert_eq!(danmaku.mode, 1);
+        assert_eq!(danmaku.color, 0xFFFFFF);
+        assert_eq!(danmaku.timestamp.timestamp_millis(), 1650000000123);
+        assert_eq!(danmaku.emoticon, None);
+        assert_eq!(
+            danmaku.sender,
+            DanmakuSender {
+                uid: 123456,
+                hash: "8a3f1e2c".to_string(),
+                name: "某用户".to_string(),
+                is_admin: true
+            }
+        );
+        let medal = danmaku.medal.unwrap();
+        assert_eq!(medal.level, 21);
+        assert_eq!(medal.room_id, 21133);
+        assert_eq!(danmaku.user_level, 25);
+        assert_eq!(danmaku.guard_level, 3);
+    }
+
+    #[test]
+    fn test_decode_emoticon_danmaku() {
+        let body = r#"{"cmd":"DANMU_MSG","info":[[0,1,25,16777215,1650000000123,1650000000,0,"8a3f1e2c",0,0,0,"",1,{"bulge_display":1,"emoticon_unique":"official_147","height":60,"in_player_area":1,"is_dynamic":1,"url":"http://i0.hdslb.com/bfs/live/a.png","width":150},"{}",{}],"赞",[1,"a",0,0,0,10000,1,""],[],[0,0,9868950,">50000",0],["",""],0,0,null,{"ts":1650000000,"ct":"ABCD"},0,0,null,null,0,105]}"#;
+        let event = LiveEvent::from_json(body).unwrap();
+        let danmaku = match event {
+            LiveEvent::Danmaku(d) => d,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(danmaku.medal, None);
+        let emoticon = danmaku.emoticon.unwrap();
+        assert_eq!(emoticon.unique, "official_147");
+        assert_eq!(emoticon.width, 150);
+    }
+
+    #[test]
+    fn test_decode_gift() {
+        let body = r#"{"cmd":"SEND_GIFT","data":{"action":"投喂","coin_type":"gold","face":"http://a.jpg","giftId":31036,"giftName":"小花花","medal_info":{"anchor_roomid":0,"anchor_uname":"","medal_level":0,"medal_name":""},"num":3,"price":100,"timestamp":1650000000,"total_coin":300,"uid":1,"uname":"someone"}}"#;
+        match LiveEvent::from_json(body).unwrap() {
+            LiveEvent::Gift(gift) => {
+                assert_eq!(gift.gift_name, "小花花");
+                assert_eq!(gift.total_coin, 300);
+                assert_eq!(gift.medal, None);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_decode_room_id_string_or_number() {
+        match LiveEvent::from_json(r#"{"cmd":"LIVE","roomid":5440}"#).unwrap() {
+            LiveEvent::Live { room_id } => assert_eq!(room_id, 5440),
+            e => panic!("unexpected event {:?}", e),
+        }
+        match LiveEvent::from_json(r#"{"cmd":"PREPARING","roomid":"5440"}"#).unwrap() {
+            LiveEvent::Preparing { room_id } => assert_eq!(room_id, 5440),
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        let event = LiveEvent::from_json(r#"{"cmd":"STOP_LIVE_ROOM_LIST","data":{}}"#).unwrap();
+        assert_eq!(event.cmd(), "STOP_LIVE_ROOM_LIST");
+        assert!(matches!(event, LiveEvent::Unknown { .. }));
+    }
+
+    #[test]
+    fn test_decode_schema_mismatch() {
+        // 已知的 cmd 格式变了，退化为 Unknown 而不是报错
+        let body = r#"{"cmd":"DANMU_MSG:4:0:2:2:2:0","info":{"text":"草"}}"#;
+        match LiveEvent::from_json(body).unwrap() {
+            LiveEvent::Unknown { cmd, raw } => {
+                assert_eq!(cmd, "DANMU_MSG:4:0:2:2:2:0");
+                assert_eq!(raw["info"]["text"], "草");
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(LiveEvent::from_json("not json").is_err());
+    }
+}